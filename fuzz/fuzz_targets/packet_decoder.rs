@@ -0,0 +1,12 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use ping_tunnel::tunnel::codec::TunnelCodec;
+use tokio_util::codec::Decoder;
+
+fuzz_target!(|data: &[u8]| {
+    let mut codec = TunnelCodec::default();
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(_packet)) = codec.decode(&mut buf) {}
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ping_tunnel::tunnel::sniff::sniff_bytes;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = sniff_bytes(data);
+});
@@ -3,7 +3,11 @@
 export declare class EdgeClient {
   constructor(serverAddr: string, token: string, forwardTo: string)
   connect(): void
+  setLegacyAuth(enabled: boolean): void
+  setTls(tlsJson: string): void
+  setForwardOverrides(overridesJson: string): void
   disconnect(): void
+  setSessionMeta(metaJson: string): Promise<string | null>
   invoke(command: string, data: string): string
 }
 
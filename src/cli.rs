@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Positional arguments plus `--name value` / `--name=value` options.
+pub struct Args {
+    pub positional: Vec<String>,
+    options: HashMap<String, Vec<String>>,
+}
+
+impl Args {
+    /// `options` lists the options that take a value and `switches` those that
+    /// take none. Any other `--name` is an error, so a typo is not silently ignored.
+    pub fn parse(
+        args: impl IntoIterator<Item = String>,
+        options: &[&str],
+        switches: &[&str],
+    ) -> anyhow::Result<Self> {
+        let mut positional = Vec::new();
+        let mut values: HashMap<String, Vec<String>> = HashMap::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                positional.push(arg);
+                continue;
+            };
+            let known = |name: &str| options.contains(&name) || switches.contains(&name);
+            if !known(name.split_once('=').map_or(name, |(name, _)| name)) {
+                return Err(anyhow::anyhow!("Unknown option --{}", name));
+            }
+            let (name, value) = match name.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None if switches.contains(&name) => (name.to_string(), "true".to_string()),
+                None => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("Missing value for --{}", name))?;
+                    (name.to_string(), value)
+                }
+            };
+            values.entry(name).or_default().push(value);
+        }
+        Ok(Self {
+            positional,
+            options: values,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.options
+            .get(name)
+            .and_then(|values| values.last())
+            .map(|v| v.as_str())
+    }
+
+    pub fn get_all(&self, name: &str) -> Vec<String> {
+        self.options.get(name).cloned().unwrap_or_default()
+    }
+
+    pub fn get_parsed<T>(&self, name: &str) -> anyhow::Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        self.get(name)
+            .map(|v| {
+                v.parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid value for --{}: {}", name, e))
+            })
+            .transpose()
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.get(name).is_some_and(|v| v != "false")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> anyhow::Result<Args> {
+        Args::parse(
+            args.iter().map(|v| v.to_string()),
+            &["tokens", "tag"],
+            &["insecure"],
+        )
+    }
+
+    #[test]
+    fn parses_options_switches_and_positional() {
+        let args = parse(&[
+            "bin",
+            "a",
+            "--tokens",
+            "t.json",
+            "--tag=x",
+            "--tag",
+            "y",
+            "--insecure",
+        ])
+        .unwrap();
+        assert_eq!(args.positional, vec!["bin", "a"]);
+        assert_eq!(args.get("tokens"), Some("t.json"));
+        assert_eq!(args.get_all("tag"), vec!["x", "y"]);
+        assert!(args.flag("insecure"));
+        assert!(parse(&["bin", "--tokens"]).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        let err = parse(&["bin", "--token", "t.json"]).err().unwrap();
+        assert_eq!(err.to_string(), "Unknown option --token");
+        assert!(parse(&["bin", "--token=t.json"]).is_err());
+        assert!(parse(&["bin", "--insecur"]).is_err());
+    }
+}
@@ -1,17 +1,84 @@
-use ping_tunnel::tunnel::edge::start_client;
+use ping_tunnel::cli::Args;
+use ping_tunnel::transport::cert::ClientTlsConfig;
+use ping_tunnel::tunnel::codec::set_max_data_len;
+use ping_tunnel::tunnel::edge::{
+    set_client_tls, set_forward_overrides, set_legacy_auth, set_session_meta, start_client,
+};
+use ping_tunnel::tunnel::egress::ForwardOverrides;
+use ping_tunnel::tunnel::payload::SessionMetaUpdate;
 use std::env;
 
+const OPTIONS: &[&str] = &[
+    "max-data-len",
+    "device-name",
+    "display-name",
+    "tag",
+    "server-name",
+    "ca",
+    "pin",
+    "known-hosts",
+    "client-cert",
+    "client-key",
+    "allow-forward",
+    "service",
+];
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args: Vec<String> = env::args().collect();
+    let args = Args::parse(env::args(), OPTIONS, &["legacy-auth", "insecure"])?;
 
-    if args.len() != 4 {
-        eprintln!("Usage: {} <server_addr:port> <token> <forward_to>", args[0]);
+    if args.positional.len() != 4 {
+        eprintln!(
+            "Usage: {} <server_addr:port> <token> <forward_to> [--max-data-len <bytes>] \
+             [--device-name <name>] [--display-name <name>] [--tag <tag>]... [--legacy-auth] \
+             [--server-name <name>] [--ca <pem> | --pin <sha256/...>... | --known-hosts <file> | --insecure] \
+             [--client-cert <pem> --client-key <pem>] [--allow-forward <host:port>]... \
+             [--service <name=host:port>]...",
+            args.positional[0]
+        );
         std::process::exit(1);
     }
+    if let Some(len) = args.get_parsed("max-data-len")? {
+        set_max_data_len(len);
+    }
+    set_legacy_auth(args.flag("legacy-auth"));
+    set_client_tls(ClientTlsConfig {
+        server_name: args.get("server-name").map(|v| v.to_string()),
+        ca_file: args.get("ca").map(|v| v.to_string()),
+        pins: args.get_all("pin"),
+        known_hosts: args.get("known-hosts").map(|v| v.to_string()),
+        insecure: args.flag("insecure"),
+        client_cert: args.get("client-cert").map(|v| v.to_string()),
+        client_key: args.get("client-key").map(|v| v.to_string()),
+    });
+    let services = args
+        .get_all("service")
+        .into_iter()
+        .map(|service| {
+            service
+                .split_once('=')
+                .map(|(name, target)| (name.to_string(), target.to_string()))
+                .ok_or(anyhow::anyhow!(
+                    "Invalid --service {}, expected name=host:port",
+                    service
+                ))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    set_forward_overrides(ForwardOverrides {
+        allow: args.get_all("allow-forward"),
+        services,
+    });
+    let tags = args.get_all("tag");
+    set_session_meta(SessionMetaUpdate {
+        device_name: args.get("device-name").map(|v| v.to_string()),
+        display_name: args.get("display-name").map(|v| v.to_string()),
+        tags: (!tags.is_empty()).then_some(tags),
+        ..Default::default()
+    })
+    .await?;
 
-    let server_addr = args[1].clone();
-    let token = args[2].clone();
-    let forward_to = args[3].clone();
+    let server_addr = args.positional[1].clone();
+    let token = args.positional[2].clone();
+    let forward_to = args.positional[3].clone();
     start_client(server_addr, token, forward_to).await
 }
@@ -1,12 +1,25 @@
+pub mod cli;
+
 pub mod tunnel {
+    pub mod acme;
+    pub mod admin;
+    pub mod authorizer;
+    pub mod codec;
     pub mod common;
+    pub mod control;
     pub mod edge;
+    pub mod egress;
+    pub mod error;
     pub mod inbound;
     pub mod outbound;
     pub mod packet;
+    pub mod payload;
+    pub mod relay;
     pub mod session;
     pub mod sniff;
     pub mod supernode;
+    pub mod token;
+    pub mod version;
 }
 
 pub mod transport {
@@ -64,11 +77,54 @@ impl EdgeClient {
         Ok(())
     }
 
+    /// Sends the token itself instead of answering an auth challenge, for
+    /// supernodes that predate challenge auth. Call before `connect`.
+    #[napi]
+    pub fn set_legacy_auth(&self, enabled: bool) {
+        crate::tunnel::edge::set_legacy_auth(enabled);
+    }
+
+    /// Sets how the supernode's certificate is verified, as JSON with
+    /// `server_name`, `ca_file`, `pins`, `known_hosts` or `insecure`. Call before `connect`.
+    #[napi]
+    pub fn set_tls(&self, tls_json: String) -> napi::Result<()> {
+        let config =
+            serde_json::from_str(&tls_json).map_err(|e| napi::Error::from_reason(e.to_string()))?;
+        crate::tunnel::edge::set_client_tls(config);
+        Ok(())
+    }
+
+    /// Sets which targets public clients may ask for with `X-Tunnel-Forward-To`,
+    /// as JSON `{"allow": ["127.0.0.1:*"], "services": {"db": "10.0.0.5:5432"}}`.
+    #[napi]
+    pub fn set_forward_overrides(&self, overrides_json: String) -> napi::Result<()> {
+        let overrides = serde_json::from_str(&overrides_json)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+        crate::tunnel::edge::set_forward_overrides(overrides);
+        Ok(())
+    }
+
     #[napi]
     pub fn disconnect(&self) -> napi::Result<()> {
         Ok(())
     }
 
+    /// Merges `meta_json` (display_name, device_name, tags, services) into this
+    /// edge's session on the supernode. Resolves to the merged meta as JSON, or
+    /// `null` while disconnected, in which case it is sent on the next connect.
+    #[napi]
+    pub async fn set_session_meta(&self, meta_json: String) -> napi::Result<Option<String>> {
+        let update = serde_json::from_str(&meta_json)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+        let merged = crate::tunnel::edge::set_session_meta(update)
+            .await
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+        merged
+            .map(|meta| serde_json::to_string(&meta))
+            .transpose()
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
     #[napi]
     pub async fn get_inbound_addr(&self) -> napi::Result<String> {
         Ok(crate::tunnel::inbound::TCP_INBOUND_ADDR
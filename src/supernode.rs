@@ -1,19 +1,267 @@
-use ping_tunnel::tunnel::supernode::start_server;
+use ping_tunnel::cli::Args;
+use ping_tunnel::tunnel::acme::{AcmeConfig, LETS_ENCRYPT_DIRECTORY};
+use ping_tunnel::tunnel::admin::{AdminConfig, start_admin};
+use ping_tunnel::tunnel::authorizer::{
+    AuthorizerBackend, ExternalAuthorizer, set_external_authorizer,
+};
+use ping_tunnel::tunnel::codec::set_max_data_len;
+use ping_tunnel::tunnel::common::unix_millis;
+use ping_tunnel::tunnel::egress::{EgressPolicy, EgressRule, set_egress_policy};
+use ping_tunnel::tunnel::inbound::TlsTermination;
+use ping_tunnel::tunnel::supernode::{
+    ClientCertAuth, ClientCertMode, DuplicateClientPolicy, set_client_cert_auth,
+    set_duplicate_client_policy, set_legacy_auth, set_self_signed_sans, set_tls_termination,
+    start_server,
+};
+use ping_tunnel::tunnel::token::{
+    FileTokenStore, SignedTokenStore, TokenClaims, TokenLimits, TokenStore, TokenStoreChain,
+    set_token_store,
+};
 use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Options of the server and of `token issue`.
+const OPTIONS: &[&str] = &[
+    "max-data-len",
+    "cert-san",
+    "duplicate-client",
+    "tls-cert-dir",
+    "tls-terminate",
+    "acme-domain",
+    "acme-on-demand",
+    "acme-directory",
+    "acme-email",
+    "acme-ca",
+    "client-ca",
+    "client-cert-auth",
+    "tokens",
+    "token-secret",
+    "token-secret-file",
+    "authorizer-url",
+    "authorizer-exec",
+    "authorizer-ttl",
+    "admin-addr",
+    "admin-token",
+    "egress-allow",
+    "egress-deny",
+    "client-id",
+    "tunnel",
+    "ttl",
+    "max-streams",
+];
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args: Vec<String> = env::args().collect();
+    let args = Args::parse(
+        env::args(),
+        OPTIONS,
+        &["disable-legacy-auth", "egress-allow-private"],
+    )?;
+    if args.positional.get(1).map(|v| v.as_str()) == Some("token") {
+        return issue_token(&args);
+    }
 
     let mut quic_bind_addr = "0.0.0.0:4433".to_string();
     let mut tcp_bind_addr = "0.0.0.0:4432".to_string();
     let mut cert_path = "./cert/cert.pem".to_string();
     let mut key_path = "./cert/key.pem".to_string();
-    if args.len() == 5 {
-        quic_bind_addr = args[1].clone();
-        tcp_bind_addr = args[2].clone();
-        cert_path = args[3].clone();
-        key_path = args[4].clone();
+    if args.positional.len() == 5 {
+        quic_bind_addr = args.positional[1].clone();
+        tcp_bind_addr = args.positional[2].clone();
+        cert_path = args.positional[3].clone();
+        key_path = args.positional[4].clone();
+    }
+    if let Some(len) = args.get_parsed("max-data-len")? {
+        set_max_data_len(len);
+    }
+    set_legacy_auth(!args.flag("disable-legacy-auth"));
+    let cert_sans = args.get_all("cert-san");
+    if !cert_sans.is_empty() {
+        set_self_signed_sans(cert_sans);
+    }
+    if let Some(policy) = args.get_parsed::<DuplicateClientPolicy>("duplicate-client")? {
+        set_duplicate_client_policy(policy);
+    }
+    let terminate = args.get_all("tls-terminate");
+    let acme = acme_from_args(&args)?;
+    match args.get("tls-cert-dir") {
+        Some(cert_dir) => set_tls_termination(Some(TlsTermination {
+            cert_dir: cert_dir.to_string(),
+            tunnels: if terminate.is_empty() {
+                vec!["*".to_string()]
+            } else {
+                terminate
+            },
+            acme,
+        })),
+        None if !terminate.is_empty()
+            || ACME_OPTIONS.iter().any(|name| args.get(name).is_some()) =>
+        {
+            return Err(anyhow::anyhow!(
+                "--tls-terminate and --acme-* require --tls-cert-dir"
+            ));
+        }
+        None => {}
+    }
+    if let Some(policy) = egress_from_args(&args)? {
+        set_egress_policy(policy);
+    }
+    if let Some(ca_path) = args.get("client-ca") {
+        set_client_cert_auth(Some(ClientCertAuth {
+            ca_path: ca_path.to_string(),
+            mode: args
+                .get_parsed::<ClientCertMode>("client-cert-auth")?
+                .unwrap_or_default(),
+        }));
+    }
+    let mut stores: Vec<Arc<dyn TokenStore>> = Vec::new();
+    if let Some(secret) = token_secret(&args)? {
+        stores.push(Arc::new(SignedTokenStore::new(&secret)));
+    }
+    if let Some(path) = args.get("tokens") {
+        let store = FileTokenStore::load(path)?;
+        store.watch(Duration::from_secs(5));
+        stores.push(store);
+    }
+    let authorizer = match (args.get("authorizer-url"), args.get("authorizer-exec")) {
+        (Some(_), Some(_)) => {
+            return Err(anyhow::anyhow!(
+                "--authorizer-url and --authorizer-exec are mutually exclusive"
+            ));
+        }
+        (Some(url), None) => Some(AuthorizerBackend::Http(url.to_string())),
+        (None, Some(program)) => Some(AuthorizerBackend::Exec(program.to_string())),
+        (None, None) => None,
+    };
+    if let Some(backend) = authorizer {
+        let ttl = Duration::from_secs(args.get_parsed("authorizer-ttl")?.unwrap_or(60));
+        set_external_authorizer(Some(Arc::new(ExternalAuthorizer::new(backend, ttl)?)));
+        set_token_store(Arc::new(TokenStoreChain(stores)));
+    } else if stores.is_empty() {
+        eprintln!(
+            "[Supernode] WARNING: neither --tokens nor --token-secret given, every token is accepted and used as its own client id, edges must connect with --legacy-auth"
+        );
+    } else {
+        set_token_store(Arc::new(TokenStoreChain(stores)));
+    }
+    if let Some(admin_addr) = args.get("admin-addr") {
+        let config = AdminConfig {
+            admin_addr: admin_addr.to_string(),
+            admin_token: args.get("admin-token").map(|v| v.to_string()),
+        };
+        config.check().await?;
+        tokio::spawn(async move {
+            if let Err(e) = start_admin(config).await {
+                eprintln!("[Admin] Error: {:?}", e);
+            }
+        });
     }
     start_server(quic_bind_addr, tcp_bind_addr, cert_path, key_path).await
 }
+
+const ACME_OPTIONS: &[&str] = &[
+    "acme-domain",
+    "acme-on-demand",
+    "acme-directory",
+    "acme-email",
+    "acme-ca",
+];
+
+/// `--acme-domain <name>... --acme-on-demand <suffix>... --acme-directory <url>
+/// --acme-email <email>... --acme-ca <pem>`, or `None` when no name is given.
+fn acme_from_args(args: &Args) -> anyhow::Result<Option<AcmeConfig>> {
+    let domains = args.get_all("acme-domain");
+    let on_demand_suffixes: Vec<String> = args
+        .get_all("acme-on-demand")
+        .iter()
+        .map(|suffix| suffix.trim_start_matches('.').to_ascii_lowercase())
+        .collect();
+    if domains.is_empty() && on_demand_suffixes.is_empty() {
+        if let Some(name) = ACME_OPTIONS.iter().find(|name| args.get(name).is_some()) {
+            return Err(anyhow::anyhow!(
+                "--{} requires --acme-domain or --acme-on-demand",
+                name
+            ));
+        }
+        return Ok(None);
+    }
+    Ok(Some(AcmeConfig {
+        directory_url: args
+            .get("acme-directory")
+            .unwrap_or(LETS_ENCRYPT_DIRECTORY)
+            .to_string(),
+        domains,
+        on_demand_suffixes,
+        contact: args
+            .get_all("acme-email")
+            .iter()
+            .map(|email| format!("mailto:{}", email))
+            .collect(),
+        ca_file: args.get("acme-ca").map(|v| v.to_string()),
+    }))
+}
+
+fn token_secret(args: &Args) -> anyhow::Result<Option<Vec<u8>>> {
+    if let Some(path) = args.get("token-secret-file") {
+        let secret = std::fs::read_to_string(path)?;
+        return Ok(Some(secret.trim().as_bytes().to_vec()));
+    }
+    Ok(args.get("token-secret").map(|v| v.as_bytes().to_vec()))
+}
+
+/// `--egress-allow <rule>... --egress-deny <rule>... --egress-allow-private`,
+/// or `None` when none of them is given.
+fn egress_from_args(args: &Args) -> anyhow::Result<Option<EgressPolicy>> {
+    let allow = args.get_all("egress-allow");
+    let deny = args.get_all("egress-deny");
+    let allow_private = args.flag("egress-allow-private");
+    if allow.is_empty() && deny.is_empty() && !allow_private {
+        return Ok(None);
+    }
+    let parse = |rules: Vec<String>| {
+        rules
+            .iter()
+            .map(|rule| rule.parse::<EgressRule>())
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+    Ok(Some(EgressPolicy {
+        allow: parse(allow)?,
+        deny: parse(deny)?,
+        block_private: !allow_private,
+    }))
+}
+
+/// `supernode token issue --client-id <id> [--tunnel <name>]... [--ttl <secs>]
+/// [--max-streams <n>] [--egress-allow <rule>]... [--egress-deny <rule>]... [--egress-allow-private]
+/// (--token-secret <secret> | --token-secret-file <path>)`
+fn issue_token(args: &Args) -> anyhow::Result<()> {
+    if args.positional.get(2).map(|v| v.as_str()) != Some("issue") {
+        return Err(anyhow::anyhow!(
+            "Usage: {} token issue --client-id <id> [--tunnel <name>]... [--ttl <secs>] \
+             [--max-streams <n>] [--egress-allow <rule>]... [--egress-deny <rule>]... \
+             [--egress-allow-private] (--token-secret <secret> | --token-secret-file <path>)",
+            args.positional[0]
+        ));
+    }
+    let secret = token_secret(args)?.ok_or(anyhow::anyhow!(
+        "--token-secret or --token-secret-file is required"
+    ))?;
+    let client_id = args
+        .get("client-id")
+        .ok_or(anyhow::anyhow!("--client-id is required"))?;
+    let now = unix_millis() / 1000;
+    let ttl: Option<u64> = args.get_parsed("ttl")?;
+    let claims = TokenClaims {
+        client_id: client_id.to_string(),
+        tunnels: args.get_all("tunnel"),
+        iat: Some(now),
+        exp: ttl.map(|ttl| now + ttl),
+        limits: TokenLimits {
+            max_streams: args.get_parsed("max-streams")?,
+        },
+        egress: egress_from_args(args)?,
+    };
+    println!("{}", SignedTokenStore::new(&secret).issue(&claims)?);
+    Ok(())
+}
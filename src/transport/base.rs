@@ -1,7 +1,34 @@
+use crate::transport::cert::ClientTlsConfig;
+use rustls::pki_types::CertificateDer;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
 
-pub trait TransportStream: AsyncWrite + AsyncRead + Unpin + Send + Sync {}
+pub trait TransportStream: AsyncWrite + AsyncRead + Unpin + Send + Sync + 'static {
+    /// Splits the stream so each direction can be finished or aborted on its own.
+    fn into_split(self: Box<Self>) -> (Box<dyn TransportRecvStream>, Box<dyn TransportSendStream>) {
+        let (reader, writer) = tokio::io::split(self);
+        (Box::new(reader), Box::new(writer))
+    }
+}
+
+pub trait TransportRecvStream: AsyncRead + Unpin + Send + Sync {
+    /// Tells the peer to stop sending; its writes fail with `code`.
+    fn stop(&mut self, _code: u32) {}
+}
+
+pub trait TransportSendStream: AsyncWrite + Unpin + Send + Sync {
+    /// Abandons the stream instead of finishing it; the peer reads a reset with `code`.
+    fn reset(&mut self, _code: u32) {}
+}
+
+impl<T: AsyncRead + Unpin + Send + Sync> TransportRecvStream for ReadHalf<T> {}
+
+impl<T: AsyncWrite + Unpin + Send + Sync> TransportSendStream for WriteHalf<T> {}
+
+/// In-memory stream for tests.
+#[cfg(test)]
+impl TransportStream for tokio::io::DuplexStream {}
 
 pub enum TransportKind {
     QUIC,
@@ -9,13 +36,36 @@ pub enum TransportKind {
 #[async_trait::async_trait]
 pub trait TransportConnection: Send + Sync {
     fn kind(&self) -> TransportKind;
+    /// Identifier that is unique among the live connections of an endpoint.
+    fn id(&self) -> usize;
     async fn open_stream(&self) -> anyhow::Result<Box<dyn TransportStream>>;
+    /// Closes the whole connection, telling the peer why.
+    fn close(&self, code: u32, reason: &str);
+    /// Fills `output` with keying material exported from the TLS session
+    /// (RFC 5705), which both ends derive identically and nobody else can.
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> anyhow::Result<()>;
+    /// Certificate chain the peer authenticated with, if it presented one.
+    fn peer_certificates(&self) -> Option<Vec<CertificateDer<'static>>>;
+    fn remote_addr(&self) -> SocketAddr;
+    /// Application close code and reason, once the peer has closed the connection.
+    fn peer_close(&self) -> Option<(u32, String)>;
 }
 
 pub struct ServerConfig {
     pub addr: String,
     pub ssl_cert_path: String,
     pub ssl_key_path: String,
+    /// Names of the self-signed certificate generated when neither file exists.
+    pub self_signed_sans: Vec<String>,
+    /// Asks edges for a certificate issued by a CA in this PEM file.
+    pub client_ca_path: Option<String>,
+    /// Refuses the handshake of edges that present no certificate.
+    pub require_client_cert: bool,
 }
 
 #[async_trait::async_trait]
@@ -39,6 +89,7 @@ pub trait TransformServer: Send + Sync {
 #[derive(Clone)]
 pub struct ClientConfig {
     pub addr: String,
+    pub tls: ClientTlsConfig,
 }
 #[async_trait::async_trait]
 pub trait TransformClient: Send + Sync + std::any::Any {
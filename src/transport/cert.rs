@@ -1,11 +1,28 @@
-use rustls::ClientConfig as RustlsClientConfig;
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use aws_lc_rs::digest;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{WebPkiSupportedAlgorithms, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientConfig as RustlsClientConfig, RootCertStore};
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
-use std::sync::{Arc, Once};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Once, RwLock};
+use std::time::{Duration, SystemTime};
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
 
 static CRYPTO_PROVIDER_INIT: Once = Once::new();
 
+/// How often certificate files are checked for changes.
+pub const CERT_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
 pub fn install_default_crypto_provider() {
     CRYPTO_PROVIDER_INIT.call_once(|| {
         rustls::crypto::aws_lc_rs::default_provider()
@@ -14,10 +31,12 @@ pub fn install_default_crypto_provider() {
     });
 }
 
+/// Loads the certificate chain (leaf first, then intermediates) and its
+/// PKCS#8, PKCS#1 (RSA) or SEC1 (EC) private key, checking that they belong together.
 pub fn load_cert(
     cert_path: String,
     key_path: String,
-) -> anyhow::Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+) -> anyhow::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
     install_default_crypto_provider();
     if !Path::new(&cert_path).exists() || !Path::new(&key_path).exists() {
         return Err(anyhow::anyhow!(
@@ -26,28 +45,304 @@ pub fn load_cert(
             key_path
         ));
     }
-    if !Path::new(&cert_path).exists() || !Path::new(&key_path).exists() {
-        return Err(anyhow::anyhow!(
-            "Certificate files not found. Please place {} and {} in the cert/ directory.",
+
+    println!("Loading certificate from {} and {}", cert_path, key_path);
+    let cert_chain = load_cert_chain(&cert_path)?;
+    let key_der = load_private_key(&key_path)?;
+    certified_key(cert_chain.clone(), key_der.clone_key()).map_err(|e| {
+        anyhow::anyhow!(
+            "Certificate {} cannot be used with private key {}: {}",
             cert_path,
-            key_path
-        ));
+            key_path,
+            e
+        )
+    })?;
+    Ok((cert_chain, key_der))
+}
+
+/// Writes a new ECDSA P-256 key and a self-signed certificate for `sans` (DNS
+/// names or IP addresses), readable only by the owner.
+pub fn generate_self_signed(
+    cert_path: &str,
+    key_path: &str,
+    sans: &[String],
+) -> anyhow::Result<()> {
+    let key = rcgen::KeyPair::generate()?;
+    let mut params = rcgen::CertificateParams::new(sans.to_vec())?;
+    params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, "ping-tunnel supernode");
+    let cert = params.self_signed(&key)?;
+    for (path, pem) in [(key_path, key.serialize_pem()), (cert_path, cert.pem())] {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        write_private(path, pem.as_bytes())
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path, e))?;
     }
+    println!(
+        "Generated a self-signed certificate for {:?} in {} and {}",
+        sans, cert_path, key_path
+    );
+    Ok(())
+}
 
-    println!("Loading certificate from {} and {}", cert_path, key_path);
-    let cert_pem = fs::read(&cert_path)?;
-    let key_pem = fs::read(&key_path)?;
-    let cert_der = rustls_pemfile::certs(&mut cert_pem.as_slice())
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("No certificate found in {}", cert_path))??;
-    let cert_der = CertificateDer::from(cert_der);
+/// `localhost`, `127.0.0.1`, `::1` and this machine's hostname.
+pub fn default_self_signed_sans() -> Vec<String> {
+    let mut sans = vec![
+        "localhost".to_string(),
+        "127.0.0.1".to_string(),
+        "::1".to_string(),
+    ];
+    if let Some(hostname) = hostname::get().ok().and_then(|h| h.into_string().ok())
+        && !sans.contains(&hostname)
+    {
+        sans.push(hostname);
+    }
+    sans
+}
 
-    let key_der = rustls_pemfile::pkcs8_private_keys(&mut key_pem.as_slice())
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("No private key found in {}", key_path))??;
-    let key_der = PrivateKeyDer::Pkcs8(key_der.into());
+/// Creates `path` with mode 0600, failing if it already exists.
+pub fn write_private(path: &str, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)
+}
+
+/// Pairs a chain with its key, failing when the key is not the leaf's.
+fn certified_key(
+    cert_chain: Vec<CertificateDer<'static>>,
+    key_der: PrivateKeyDer<'static>,
+) -> anyhow::Result<CertifiedKey> {
+    let provider = rustls::crypto::CryptoProvider::get_default()
+        .ok_or(anyhow::anyhow!("No crypto provider installed"))?;
+    let key = provider.key_provider.load_private_key(key_der)?;
+    let certified_key = CertifiedKey::new(cert_chain, key);
+    match certified_key.keys_match() {
+        Ok(()) => Ok(certified_key),
+        Err(rustls::Error::InconsistentKeys(rustls::InconsistentKeys::KeyMismatch)) => Err(
+            anyhow::anyhow!("the private key does not match the certificate"),
+        ),
+        // Keys whose public half cannot be derived are taken on trust.
+        Err(rustls::Error::InconsistentKeys(rustls::InconsistentKeys::Unknown)) => {
+            Ok(certified_key)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Serves the certificate in `cert_path`/`key_path` and swaps it for new
+/// handshakes when the files change or on SIGHUP, so renewing it needs no
+/// restart. Established connections keep the certificate they started with.
+#[derive(Debug)]
+pub struct ReloadingCertResolver {
+    cert_path: String,
+    key_path: String,
+    current: RwLock<Arc<CertifiedKey>>,
+    modified: Mutex<Option<(SystemTime, SystemTime)>>,
+}
+
+impl ReloadingCertResolver {
+    pub fn load(cert_path: &str, key_path: &str) -> anyhow::Result<Arc<Self>> {
+        let resolver = Arc::new(Self {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            current: RwLock::new(Arc::new(Self::load_key(cert_path, key_path)?)),
+            modified: Mutex::new(None),
+        });
+        *resolver.modified.lock().unwrap() = resolver.modified_times();
+        Ok(resolver)
+    }
+
+    fn load_key(cert_path: &str, key_path: &str) -> anyhow::Result<CertifiedKey> {
+        let (cert_chain, key_der) = load_cert(cert_path.to_string(), key_path.to_string())?;
+        certified_key(cert_chain, key_der)
+    }
+
+    fn modified_times(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &str| fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((modified(&self.cert_path)?, modified(&self.key_path)?))
+    }
+
+    pub fn certificate(&self) -> Arc<CertifiedKey> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Re-reads both files. On error the previous certificate stays in use.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let modified = self.modified_times();
+        let certified_key = Self::load_key(&self.cert_path, &self.key_path)?;
+        println!(
+            "Reloaded certificate, key fingerprint (for --pin): {}",
+            spki_fingerprint(certified_key.end_entity_cert()?)?
+        );
+        *self.current.write().unwrap() = Arc::new(certified_key);
+        *self.modified.lock().unwrap() = modified;
+        Ok(())
+    }
+
+    /// Polls the files every `interval` and reloads them when they changed,
+    /// and reloads on SIGHUP.
+    pub fn watch(self: &Arc<Self>, interval: Duration) {
+        let resolver = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let modified = resolver.modified_times();
+                if modified.is_none() || modified == *resolver.modified.lock().unwrap() {
+                    continue;
+                }
+                if let Err(e) = resolver.reload() {
+                    eprintln!("Failed to reload certificate: {}", e);
+                }
+            }
+        });
+        let resolver = self.clone();
+        reload_on_hangup("certificate", move || resolver.reload());
+    }
+}
+
+/// Calls `reload` on every SIGHUP.
+fn reload_on_hangup<F>(what: &'static str, reload: F)
+where
+    F: Fn() -> anyhow::Result<()> + Send + 'static,
+{
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                eprintln!("Failed to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            println!("SIGHUP received, reloading {}", what);
+            if let Err(e) = reload() {
+                eprintln!("Failed to reload {}: {}", what, e);
+            }
+        }
+    });
+    #[cfg(not(unix))]
+    let _ = (what, reload);
+}
 
-    Ok((cert_der, key_der))
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.certificate())
+    }
+}
+
+/// Picks a certificate by SNI from a directory where every `*.pem` file holds a
+/// chain followed by its private key. A certificate is served for the DNS names
+/// of its leaf, wildcards such as `*.example.com` included. The directory is
+/// re-read when its files change or on SIGHUP.
+#[derive(Debug)]
+pub struct CertDirResolver {
+    dir: String,
+    certs: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    modified: Mutex<Vec<(PathBuf, SystemTime)>>,
+}
+
+impl CertDirResolver {
+    pub fn load(dir: &str) -> anyhow::Result<Arc<Self>> {
+        install_default_crypto_provider();
+        let resolver = Arc::new(Self {
+            dir: dir.to_string(),
+            certs: RwLock::new(HashMap::new()),
+            modified: Mutex::new(Vec::new()),
+        });
+        resolver.reload()?;
+        Ok(resolver)
+    }
+
+    fn pem_files(&self) -> anyhow::Result<Vec<(PathBuf, SystemTime)>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", self.dir, e))?
+        {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "pem") {
+                files.push((path.clone(), fs::metadata(&path)?.modified()?));
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Re-reads the directory. Files that cannot be loaded are skipped.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let files = self.pem_files()?;
+        let mut certs = HashMap::new();
+        for (path, _) in &files {
+            let path = path.to_string_lossy();
+            let loaded = load_cert_chain(&path).and_then(|chain| {
+                let names = cert_names(&chain[0])?;
+                Ok((names, certified_key(chain, load_private_key(&path)?)?))
+            });
+            match loaded {
+                Ok((names, certified_key)) => {
+                    println!("Loaded certificate {} for {:?}", path, names);
+                    let certified_key = Arc::new(certified_key);
+                    for name in names {
+                        certs.insert(name, certified_key.clone());
+                    }
+                }
+                Err(e) => eprintln!("Skipping certificate {}: {}", path, e),
+            }
+        }
+        *self.certs.write().unwrap() = certs;
+        *self.modified.lock().unwrap() = files;
+        Ok(())
+    }
+
+    /// The certificate for `server_name`, an exact name before a wildcard.
+    pub fn find(&self, server_name: &str) -> Option<Arc<CertifiedKey>> {
+        let server_name = server_name.to_ascii_lowercase();
+        let certs = self.certs.read().unwrap();
+        certs.get(&server_name).cloned().or_else(|| {
+            let (_, parent) = server_name.split_once('.')?;
+            certs.get(&format!("*.{}", parent)).cloned()
+        })
+    }
+
+    pub fn watch(self: &Arc<Self>, interval: Duration) {
+        let resolver = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                match resolver.pem_files() {
+                    Ok(files) if files != *resolver.modified.lock().unwrap() => {}
+                    _ => continue,
+                }
+                if let Err(e) = resolver.reload() {
+                    eprintln!("Failed to reload certificates: {}", e);
+                }
+            }
+        });
+        let resolver = self.clone();
+        reload_on_hangup("certificates", move || resolver.reload());
+    }
+}
+
+impl ResolvesServerCert for CertDirResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.find(client_hello.server_name()?)
+    }
+}
+
+/// The lowercase DNS names a certificate is valid for, or its common name if it has none.
+fn cert_names(cert: &CertificateDer<'_>) -> anyhow::Result<Vec<String>> {
+    let identity = cert_identity(cert)?;
+    let names = if identity.dns_names.is_empty() {
+        identity.common_name.into_iter().collect()
+    } else {
+        identity.dns_names
+    };
+    Ok(names.iter().map(|name| name.to_ascii_lowercase()).collect())
 }
 
 #[derive(Debug)]
@@ -100,26 +395,467 @@ impl rustls::client::danger::ServerCertVerifier for NoCertificateVerification {
     }
 }
 
-pub fn get_client_crypto_config() -> anyhow::Result<quinn::crypto::rustls::QuicClientConfig> {
-    install_default_crypto_provider();
-    let mut client_config = RustlsClientConfig::builder()
-        .with_root_certificates(rustls::RootCertStore::empty())
-        .with_no_client_auth();
+/// How the edge checks the supernode's certificate. With nothing but
+/// `server_name` set, the certificate must chain to the system trust store.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ClientTlsConfig {
+    /// Name the certificate must be valid for, also sent as SNI. Defaults to
+    /// the host of the server address.
+    pub server_name: Option<String>,
+    /// PEM file with the CAs to trust instead of the system roots.
+    pub ca_file: Option<String>,
+    /// Accepts any certificate whose key has one of these fingerprints (see `spki_fingerprint`).
+    pub pins: Vec<String>,
+    /// Trusts the key a server presents the first time, records it in this
+    /// file and refuses any other key for that server afterwards.
+    pub known_hosts: Option<String>,
+    /// Accepts any certificate. Only for testing.
+    pub insecure: bool,
+    /// PEM certificate chain presented to supernodes that ask for one.
+    pub client_cert: Option<String>,
+    /// PEM private key of `client_cert`.
+    pub client_key: Option<String>,
+}
 
-    client_config
-        .dangerous()
-        .set_certificate_verifier(Arc::new(NoCertificateVerification));
+impl ClientTlsConfig {
+    /// The name to verify when connecting to `addr` (`host:port`).
+    pub fn server_name_for(&self, addr: &str) -> String {
+        if let Some(name) = &self.server_name {
+            return name.clone();
+        }
+        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        host.trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string()
+    }
+}
 
-    let quic_client_config = match quinn::crypto::rustls::QuicClientConfig::try_from(client_config)
-    {
-        Ok(config) => config,
-        Err(e) => {
-            eprintln!("Failed to create QUIC client config: {}", e);
+/// `sha256/<base64>` of the certificate's SubjectPublicKeyInfo, the format `--pin` takes.
+pub fn spki_fingerprint(cert: &CertificateDer<'_>) -> anyhow::Result<String> {
+    let cert = webpki::EndEntityCert::try_from(cert)
+        .map_err(|e| anyhow::anyhow!("Invalid certificate: {:?}", e))?;
+    let digest = digest::digest(&digest::SHA256, &cert.subject_public_key_info());
+    Ok(format!("sha256/{}", STANDARD.encode(digest)))
+}
+
+/// Builds the QUIC client crypto for connecting to `addr`, which also keys the
+/// known-hosts entry.
+pub fn client_crypto_config(
+    tls: &ClientTlsConfig,
+    addr: &str,
+) -> anyhow::Result<quinn::crypto::rustls::QuicClientConfig> {
+    install_default_crypto_provider();
+    let modes = [
+        tls.ca_file.is_some(),
+        !tls.pins.is_empty(),
+        tls.known_hosts.is_some(),
+        tls.insecure,
+    ];
+    if modes.iter().filter(|set| **set).count() > 1 {
+        return Err(anyhow::anyhow!(
+            "Only one of a CA file, pins, a known-hosts file or insecure can be used"
+        ));
+    }
+    let builder = RustlsClientConfig::builder();
+    let client_config = if tls.insecure {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
+    } else if !tls.pins.is_empty() {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(KeyVerifier::new(KeyTrust::Pinned(
+                tls.pins.clone(),
+            ))))
+    } else if let Some(path) = &tls.known_hosts {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(KeyVerifier::new(KeyTrust::KnownHosts {
+                path: path.into(),
+                host: addr.to_string(),
+            })))
+    } else {
+        builder.with_root_certificates(root_store(tls.ca_file.as_deref())?)
+    };
+    let client_config = match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => {
+            client_config.with_client_auth_cert(load_cert_chain(cert)?, load_private_key(key)?)?
+        }
+        (None, None) => client_config.with_no_client_auth(),
+        _ => {
             return Err(anyhow::anyhow!(
-                "Failed to create QUIC client config: {}",
-                e
+                "A client certificate needs both a certificate and a key file"
             ));
         }
     };
-    Ok(quic_client_config)
+    quinn::crypto::rustls::QuicClientConfig::try_from(client_config)
+        .map_err(|e| anyhow::anyhow!("Failed to create QUIC client config: {}", e))
+}
+
+pub fn load_cert_chain(path: &str) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let pem = fs::read(path).map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice()).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("No certificate found in {}", path));
+    }
+    Ok(certs)
+}
+
+pub fn load_private_key(path: &str) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let pem = fs::read(path).map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path, e))?;
+    rustls_pemfile::private_key(&mut pem.as_slice())?
+        .ok_or_else(|| anyhow::anyhow!("No private key found in {}", path))
+}
+
+/// Verifies edge certificates against the CAs in `ca_file`. Unless `required`,
+/// edges without a certificate are let through to authenticate with a token.
+pub fn client_cert_verifier(
+    ca_file: &str,
+    required: bool,
+) -> anyhow::Result<Arc<dyn ClientCertVerifier>> {
+    install_default_crypto_provider();
+    let builder = WebPkiClientVerifier::builder(Arc::new(root_store(Some(ca_file))?));
+    let builder = if required {
+        builder
+    } else {
+        builder.allow_unauthenticated()
+    };
+    Ok(builder.build()?)
+}
+
+/// Who a certificate was issued to.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CertIdentity {
+    pub common_name: Option<String>,
+    pub dns_names: Vec<String>,
+}
+
+pub fn cert_identity(cert: &CertificateDer<'_>) -> anyhow::Result<CertIdentity> {
+    let (_, cert) = X509Certificate::from_der(cert)
+        .map_err(|e| anyhow::anyhow!("Invalid certificate: {}", e))?;
+    let common_name = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(|cn| cn.to_string());
+    let dns_names = cert
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .map(|san| {
+            san.value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) => Some(name.to_string()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(CertIdentity {
+        common_name,
+        dns_names,
+    })
+}
+
+fn root_store(ca_file: Option<&str>) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(path) => {
+            for cert in load_cert_chain(path)? {
+                roots.add(cert)?;
+            }
+        }
+        None => {
+            let native = rustls_native_certs::load_native_certs();
+            for err in &native.errors {
+                eprintln!("Failed to load system certificates: {}", err);
+            }
+            roots.add_parsable_certificates(native.certs);
+        }
+    }
+    if roots.is_empty() {
+        return Err(anyhow::anyhow!("No trusted CA certificates found"));
+    }
+    Ok(roots)
+}
+
+#[derive(Debug)]
+enum KeyTrust {
+    Pinned(Vec<String>),
+    KnownHosts { path: PathBuf, host: String },
+}
+
+/// Trusts a server by the key of its certificate rather than by who issued it.
+#[derive(Debug)]
+struct KeyVerifier {
+    trust: KeyTrust,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl KeyVerifier {
+    fn new(trust: KeyTrust) -> Self {
+        Self {
+            trust,
+            algorithms: rustls::crypto::aws_lc_rs::default_provider()
+                .signature_verification_algorithms,
+        }
+    }
+
+    fn check(&self, fingerprint: &str) -> anyhow::Result<()> {
+        match &self.trust {
+            KeyTrust::Pinned(pins) => {
+                if !pins.iter().any(|pin| pin == fingerprint) {
+                    return Err(anyhow::anyhow!(
+                        "certificate key {} is not pinned",
+                        fingerprint
+                    ));
+                }
+            }
+            KeyTrust::KnownHosts { path, host } => {
+                let known = fs::read_to_string(path).unwrap_or_default();
+                let entry = known
+                    .lines()
+                    .filter_map(|line| line.split_once(' '))
+                    .find(|(known_host, _)| known_host == host);
+                match entry {
+                    Some((_, known_key)) if known_key.trim() == fingerprint => {}
+                    Some((_, known_key)) => {
+                        return Err(anyhow::anyhow!(
+                            "certificate key of {} changed from {} to {}; remove its line from {:?} if this is expected",
+                            host,
+                            known_key.trim(),
+                            fingerprint,
+                            path
+                        ));
+                    }
+                    None => {
+                        let mut file = fs::OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(path)?;
+                        writeln!(file, "{} {}", host, fingerprint)?;
+                        println!(
+                            "Trusting {} on first use, key {} saved to {:?}",
+                            host, fingerprint, path
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ServerCertVerifier for KeyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        spki_fingerprint(end_entity)
+            .and_then(|fingerprint| self.check(&fingerprint))
+            .map_err(|e| rustls::Error::General(e.to_string()))?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_name_defaults_to_host() {
+        let tls = ClientTlsConfig::default();
+        assert_eq!(
+            tls.server_name_for("tunnel.example.com:4433"),
+            "tunnel.example.com"
+        );
+        assert_eq!(tls.server_name_for("[::1]:4433"), "::1");
+        let tls = ClientTlsConfig {
+            server_name: Some("localhost".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(tls.server_name_for("127.0.0.1:4433"), "localhost");
+    }
+
+    #[test]
+    fn known_hosts_trusts_first_key_only() {
+        let path = std::env::temp_dir().join(format!("known-hosts-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let verifier = KeyVerifier::new(KeyTrust::KnownHosts {
+            path: path.clone(),
+            host: "a:4433".to_string(),
+        });
+        verifier.check("sha256/first").unwrap();
+        verifier.check("sha256/first").unwrap();
+        assert!(verifier.check("sha256/second").is_err());
+        let other = KeyVerifier::new(KeyTrust::KnownHosts {
+            path: path.clone(),
+            host: "b:4433".to_string(),
+        });
+        other.check("sha256/second").unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "a:4433 sha256/first\nb:4433 sha256/second\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_cert_returns_chain_and_checks_key() {
+        use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let leaf = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&key, &ca)
+            .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("load-cert-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        fs::write(path("chain.pem"), leaf.pem() + &ca.pem()).unwrap();
+        fs::write(path("key.pem"), key.serialize_pem()).unwrap();
+        fs::write(
+            path("other.pem"),
+            KeyPair::generate().unwrap().serialize_pem(),
+        )
+        .unwrap();
+
+        let (chain, _) = load_cert(path("chain.pem"), path("key.pem")).unwrap();
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain[0].as_ref(), leaf.der().as_ref());
+        let err = load_cert(path("chain.pem"), path("other.pem")).unwrap_err();
+        assert!(err.to_string().contains("does not match"), "{}", err);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_cert_accepts_openssl_pkcs1_and_sec1_keys() {
+        // Made with `openssl genrsa -traditional` and `openssl ecparam -genkey`.
+        let testdata = |name: &str| {
+            format!(
+                "{}/src/transport/testdata/{}",
+                env!("CARGO_MANIFEST_DIR"),
+                name
+            )
+        };
+        for (format, key_type) in [("rsa", "RSA PRIVATE KEY"), ("ec", "EC PRIVATE KEY")] {
+            let key_path = testdata(&format!("{}.key", format));
+            let pem = fs::read_to_string(&key_path).unwrap();
+            assert!(pem.starts_with(&format!("-----BEGIN {}-----", key_type)));
+            let (chain, key) = load_cert(testdata(&format!("{}.pem", format)), key_path).unwrap();
+            assert_eq!(chain.len(), 1);
+            assert!(matches!(
+                (format, &key),
+                ("rsa", PrivateKeyDer::Pkcs1(_)) | ("ec", PrivateKeyDer::Sec1(_))
+            ));
+            let err = load_cert(
+                testdata(&format!("{}.pem", format)),
+                testdata(&format!("{}-other.key", format)),
+            )
+            .unwrap_err();
+            assert!(
+                err.to_string().contains("does not match"),
+                "{}: {}",
+                format,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn cert_dir_picks_exact_name_before_wildcard() {
+        let dir = std::env::temp_dir().join(format!("cert-dir-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let write = |file: &str, names: &[&str]| {
+            let key = rcgen::KeyPair::generate().unwrap();
+            let names = names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>();
+            let cert = rcgen::CertificateParams::new(names)
+                .unwrap()
+                .self_signed(&key)
+                .unwrap();
+            fs::write(dir.join(file), cert.pem() + &key.serialize_pem()).unwrap();
+            cert.der().to_vec()
+        };
+        let wildcard = write("wildcard.pem", &["*.tunnel.test"]);
+        let exact = write("api.pem", &["API.tunnel.test"]);
+        fs::write(dir.join("broken.pem"), "not a certificate").unwrap();
+
+        let resolver = CertDirResolver::load(&dir.to_string_lossy()).unwrap();
+        let leaf = |name: &str| {
+            resolver
+                .find(name)
+                .map(|certified_key| certified_key.end_entity_cert().unwrap().to_vec())
+        };
+        assert_eq!(leaf("api.tunnel.test"), Some(exact));
+        assert_eq!(leaf("web.tunnel.test"), Some(wildcard));
+        assert_eq!(leaf("a.web.tunnel.test"), None);
+        assert_eq!(leaf("tunnel.test"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn generated_cert_is_private_and_loads() {
+        let dir = std::env::temp_dir().join(format!("self-signed-{}", std::process::id()));
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        let sans = vec!["tunnel.test".to_string(), "127.0.0.1".to_string()];
+        generate_self_signed(&path("cert.pem"), &path("key.pem"), &sans).unwrap();
+
+        let (chain, _) = load_cert(path("cert.pem"), path("key.pem")).unwrap();
+        let (_, cert) = x509_parser::parse_x509_certificate(&chain[0]).unwrap();
+        let names = cert.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(names.value.general_names.len(), 2);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(path("key.pem")).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        // Existing files are never overwritten.
+        assert!(generate_self_signed(&path("cert.pem"), &path("key.pem"), &sans).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pins_accept_only_listed_keys() {
+        let fingerprint = "sha256/pinned";
+        let verifier = KeyVerifier::new(KeyTrust::Pinned(vec![fingerprint.to_string()]));
+        verifier.check(fingerprint).unwrap();
+        assert!(verifier.check("sha256/other").is_err());
+    }
 }
@@ -1,11 +1,17 @@
 use crate::transport::base::{
     ClientConfig, ServerConfig, TransformClient, TransformServer, TransportConnection,
-    TransportKind, TransportStream,
+    TransportKind, TransportRecvStream, TransportSendStream, TransportStream,
 };
-use crate::transport::cert::NoCertificateVerification;
-use quinn::{ClientConfig as QuinnClientConfig, Endpoint, RecvStream, SendStream, VarInt};
-use rustls::ClientConfig as RustlsClientConfig;
+use crate::transport::cert::{
+    CERT_RELOAD_INTERVAL, ReloadingCertResolver, client_crypto_config, generate_self_signed,
+};
+use quinn::{
+    ClientConfig as QuinnClientConfig, ConnectionError, Endpoint, RecvStream, SendStream, VarInt,
+};
+use rustls::pki_types::CertificateDer;
 use std::{
+    net::SocketAddr,
+    path::Path,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
@@ -28,21 +34,21 @@ impl AsyncWrite for QuinnStream {
         let this = &mut *self;
         Pin::new(&mut this.send)
             .poll_write(cx, buf)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            .map_err(io::Error::other)
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         let this = &mut *self;
         Pin::new(&mut this.send)
             .poll_flush(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            .map_err(io::Error::other)
     }
 
     fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         let this = &mut *self;
         Pin::new(&mut this.send)
             .poll_shutdown(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            .map_err(io::Error::other)
     }
 }
 
@@ -57,7 +63,24 @@ impl AsyncRead for QuinnStream {
     }
 }
 
-impl TransportStream for QuinnStream {}
+impl TransportStream for QuinnStream {
+    fn into_split(self: Box<Self>) -> (Box<dyn TransportRecvStream>, Box<dyn TransportSendStream>) {
+        let QuinnStream { send, recv } = *self;
+        (Box::new(recv), Box::new(send))
+    }
+}
+
+impl TransportRecvStream for RecvStream {
+    fn stop(&mut self, code: u32) {
+        let _ = RecvStream::stop(self, code.into());
+    }
+}
+
+impl TransportSendStream for SendStream {
+    fn reset(&mut self, code: u32) {
+        let _ = SendStream::reset(self, code.into());
+    }
+}
 pub struct QuinnConnection {
     pub conn: quinn::Connection,
 }
@@ -67,6 +90,9 @@ impl TransportConnection for QuinnConnection {
     fn kind(&self) -> TransportKind {
         TransportKind::QUIC
     }
+    fn id(&self) -> usize {
+        self.conn.stable_id()
+    }
     async fn open_stream(&self) -> anyhow::Result<Box<dyn TransportStream>> {
         if let Some(reason) = self.conn.close_reason() {
             return Err(anyhow::anyhow!("Connection closed: {:?}", reason));
@@ -74,6 +100,38 @@ impl TransportConnection for QuinnConnection {
         let (send, recv) = self.conn.open_bi().await?;
         Ok(Box::new(QuinnStream { send, recv }))
     }
+    fn close(&self, code: u32, reason: &str) {
+        self.conn.close(code.into(), reason.as_bytes());
+    }
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> anyhow::Result<()> {
+        self.conn
+            .export_keying_material(output, label, context)
+            .map_err(|_| anyhow::anyhow!("Failed to export keying material"))
+    }
+    fn peer_certificates(&self) -> Option<Vec<CertificateDer<'static>>> {
+        self.conn
+            .peer_identity()?
+            .downcast::<Vec<CertificateDer<'static>>>()
+            .ok()
+            .map(|certs| *certs)
+    }
+    fn remote_addr(&self) -> SocketAddr {
+        self.conn.remote_address()
+    }
+    fn peer_close(&self) -> Option<(u32, String)> {
+        match self.conn.close_reason()? {
+            ConnectionError::ApplicationClosed(close) => Some((
+                close.error_code.into_inner() as u32,
+                String::from_utf8_lossy(&close.reason).to_string(),
+            )),
+            _ => None,
+        }
+    }
 }
 
 pub struct QuinnServerEndpoint {
@@ -86,6 +144,12 @@ impl QuinnServerEndpoint {
     }
 }
 
+impl Default for QuinnServerEndpoint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[async_trait::async_trait]
 impl TransformServer for QuinnServerEndpoint {
     async fn bind(config: ServerConfig) -> Result<Arc<Self>, anyhow::Error>
@@ -94,13 +158,32 @@ impl TransformServer for QuinnServerEndpoint {
     {
         crate::transport::cert::install_default_crypto_provider();
 
+        if !Path::new(&config.ssl_cert_path).exists() && !Path::new(&config.ssl_key_path).exists() {
+            generate_self_signed(
+                &config.ssl_cert_path,
+                &config.ssl_key_path,
+                &config.self_signed_sans,
+            )?;
+        }
         println!("Loading certificate...");
-        let (cert_der, key_der) =
-            crate::transport::cert::load_cert(config.ssl_cert_path, config.ssl_key_path)?;
-        let rustls_config = rustls::ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(vec![cert_der], key_der)
-            .unwrap();
+        let resolver = ReloadingCertResolver::load(&config.ssl_cert_path, &config.ssl_key_path)?;
+        println!(
+            "Certificate key fingerprint (for --pin): {}",
+            crate::transport::cert::spki_fingerprint(resolver.certificate().end_entity_cert()?)?
+        );
+        resolver.watch(CERT_RELOAD_INTERVAL);
+        let builder = rustls::ServerConfig::builder();
+        let builder = match &config.client_ca_path {
+            Some(path) => {
+                println!("Requesting client certificates issued by {}", path);
+                builder.with_client_cert_verifier(crate::transport::cert::client_cert_verifier(
+                    path,
+                    config.require_client_cert,
+                )?)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let rustls_config = builder.with_cert_resolver(resolver);
         let quic_server_config = quinn::crypto::rustls::QuicServerConfig::try_from(rustls_config)?;
 
         let mut transport_config = quinn::TransportConfig::default();
@@ -204,24 +287,8 @@ pub struct QuinnClientEndpoint {
 #[async_trait::async_trait]
 impl TransformClient for QuinnClientEndpoint {
     async fn connect(config: ClientConfig) -> anyhow::Result<Arc<Self>> {
-        crate::transport::cert::install_default_crypto_provider();
-
-        let mut client_config = RustlsClientConfig::builder()
-            .with_root_certificates(rustls::RootCertStore::empty())
-            .with_no_client_auth();
-
-        client_config
-            .dangerous()
-            .set_certificate_verifier(Arc::new(NoCertificateVerification));
-
-        let quic_client_config =
-            match quinn::crypto::rustls::QuicClientConfig::try_from(client_config) {
-                Ok(config) => config,
-                Err(e) => {
-                    eprintln!("Failed to create QUIC client config: {}", e);
-                    return Err(anyhow::anyhow!(e));
-                }
-            };
+        let quic_client_config = client_crypto_config(&config.tls, &config.addr)?;
+        let server_name = config.tls.server_name_for(&config.addr);
 
         let mut transport_config = quinn::TransportConfig::default();
         transport_config.keep_alive_interval(Some(std::time::Duration::from_secs(5)));
@@ -238,8 +305,16 @@ impl TransformClient for QuinnClientEndpoint {
 
         loop {
             println!("Connecting to Server: {} ...", config.addr);
-            let addr = match config.addr.parse() {
-                Ok(addr) => addr,
+            let addr = match tokio::net::lookup_host(&config.addr)
+                .await
+                .map(|mut addrs| addrs.find(|addr: &SocketAddr| addr.is_ipv4()))
+            {
+                Ok(Some(addr)) => addr,
+                Ok(None) => {
+                    eprintln!("Server address {} has no IPv4 address", config.addr);
+                    tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+                    continue;
+                }
                 Err(e) => {
                     eprintln!("Invalid server address: {}", e);
                     tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
@@ -247,7 +322,7 @@ impl TransformClient for QuinnClientEndpoint {
                 }
             };
             let conn = match tokio::time::timeout(Duration::from_secs(10), async {
-                let connecting = endpoint.connect(addr, "localhost");
+                let connecting = endpoint.connect(addr, &server_name);
                 let connecting = match connecting {
                     Ok(connecting) => connecting,
                     Err(e) => {
@@ -0,0 +1,960 @@
+use crate::transport::cert::{
+    CertDirResolver, install_default_crypto_provider, load_cert_chain, load_private_key,
+    write_private,
+};
+use aws_lc_rs::digest;
+use aws_lc_rs::rand::SystemRandom;
+use aws_lc_rs::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use dashmap::DashMap;
+use rustls::pki_types::PrivateKeyDer;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Certificates are renewed when they expire within this long.
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// How often the certificates are checked for renewal.
+const RENEW_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+/// How soon a failed issuance is retried.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// At most this many on-demand orders are placed per hour, whatever the names.
+const ON_DEMAND_PER_HOUR: usize = 10;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 30;
+
+const HTTP01_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Obtains certificates for `domains` from an ACME directory with HTTP-01
+/// challenges answered on the TCP inbound, and renews them before they expire.
+#[derive(Debug, Clone)]
+pub struct AcmeConfig {
+    pub directory_url: String,
+    /// One certificate is issued per name; wildcards need DNS-01 and are not supported.
+    pub domains: Vec<String>,
+    /// Tunnel hostnames `<tunnel>.<suffix>` get a certificate once a public
+    /// client asks for them while the tunnel is online.
+    pub on_demand_suffixes: Vec<String>,
+    /// Account contacts, such as `mailto:ops@example.com`.
+    pub contact: Vec<String>,
+    /// An extra CA to trust for the directory, such as the one of a local Pebble.
+    pub ca_file: Option<String>,
+}
+
+/// Key authorizations of pending HTTP-01 challenges, by token.
+static HTTP01_CHALLENGES: LazyLock<DashMap<String, String>> = LazyLock::new(DashMap::new);
+
+/// The body to answer a GET of `path` with, if it is a pending HTTP-01 challenge.
+pub fn http01_response(path: &str) -> Option<String> {
+    let token = path.strip_prefix(HTTP01_PREFIX)?;
+    HTTP01_CHALLENGES.get(token).map(|entry| entry.clone())
+}
+
+/// Where the certificate of `domain` is kept; `CertDirResolver` serves it from there.
+fn cert_path(cert_dir: &str, domain: &str) -> PathBuf {
+    Path::new(cert_dir).join(format!("{}.pem", domain))
+}
+
+/// Whether the certificate at `path` is missing, unreadable or expires within `RENEW_BEFORE`.
+pub fn needs_renewal(path: &Path) -> bool {
+    let Ok(chain) = load_cert_chain(&path.to_string_lossy()) else {
+        return true;
+    };
+    let Ok((_, cert)) = X509Certificate::from_der(&chain[0]) else {
+        return true;
+    };
+    let renew_at = SystemTime::now() + RENEW_BEFORE;
+    let renew_at = renew_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    cert.validity().not_after.timestamp() <= renew_at.as_secs() as i64
+}
+
+/// Keeps the certificates of `config.domains`, and those issued on demand, in
+/// `cert_dir` issued and current, reloading `resolver` whenever one changed.
+pub fn spawn_renewal(config: AcmeConfig, cert_dir: String, resolver: Arc<CertDirResolver>) {
+    tokio::spawn(async move {
+        loop {
+            let interval = match renew(&config, &cert_dir).await {
+                Ok(issued) => {
+                    if issued > 0
+                        && let Err(e) = resolver.reload()
+                    {
+                        eprintln!("[ACME] Failed to reload certificates: {}", e);
+                    }
+                    RENEW_CHECK_INTERVAL
+                }
+                Err(e) => {
+                    eprintln!("[ACME] {}", e);
+                    RETRY_INTERVAL
+                }
+            };
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+/// Issues every certificate that is due and returns how many were issued.
+/// Fails, after trying the others, if any of them could not be issued.
+async fn renew(config: &AcmeConfig, cert_dir: &str) -> anyhow::Result<usize> {
+    let mut domains = config.domains.clone();
+    domains.extend(issued_on_demand(config, cert_dir));
+    let due: Vec<String> = domains
+        .into_iter()
+        .filter(|domain| needs_renewal(&cert_path(cert_dir, domain)))
+        .collect();
+    if due.is_empty() {
+        return Ok(0);
+    }
+    let mut client = AcmeClient::new(config, cert_dir).await?;
+    let mut issued = 0;
+    let mut failed = Vec::new();
+    for domain in &due {
+        match issue_and_save(&mut client, cert_dir, domain).await {
+            Ok(()) => issued += 1,
+            Err(e) => {
+                eprintln!("[ACME] Failed to issue a certificate for {}: {}", domain, e);
+                failed.push(domain.as_str());
+            }
+        }
+    }
+    if !failed.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No certificate for {:?}, retrying in {:?}",
+            failed,
+            RETRY_INTERVAL
+        ));
+    }
+    Ok(issued)
+}
+
+async fn issue_and_save(
+    client: &mut AcmeClient,
+    cert_dir: &str,
+    domain: &str,
+) -> anyhow::Result<()> {
+    println!("[ACME] Requesting a certificate for {}", domain);
+    let pem = client.issue(domain).await?;
+    save(&cert_path(cert_dir, domain), &pem)?;
+    println!("[ACME] Issued a certificate for {}", domain);
+    Ok(())
+}
+
+/// Names in `cert_dir` that were issued on demand, so they are renewed too.
+fn issued_on_demand(config: &AcmeConfig, cert_dir: &str) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(cert_dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().to_string_lossy().to_string();
+            let domain = name.strip_suffix(".pem")?;
+            (!config.domains.iter().any(|d| d == domain)
+                && on_demand_name(&config.on_demand_suffixes, domain))
+            .then(|| domain.to_string())
+        })
+        .collect()
+}
+
+/// Whether `server_name` is a single DNS label followed by one of `suffixes`.
+fn on_demand_name(suffixes: &[String], server_name: &str) -> bool {
+    suffixes.iter().any(|suffix| {
+        server_name
+            .strip_suffix(suffix.as_str())
+            .and_then(|label| label.strip_suffix('.'))
+            .is_some_and(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label
+                        .bytes()
+                        .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+            })
+    })
+}
+
+/// Orders certificates for tunnel hostnames as public clients ask for them, one
+/// at a time and no more than `ON_DEMAND_PER_HOUR` orders an hour.
+pub struct OnDemandIssuer {
+    suffixes: Vec<String>,
+    queue: mpsc::UnboundedSender<String>,
+    /// When each name was last queued; a name is not retried within `RETRY_INTERVAL`.
+    attempts: DashMap<String, Instant>,
+    /// When the orders of the last hour were queued.
+    recent: Mutex<VecDeque<Instant>>,
+}
+
+impl OnDemandIssuer {
+    /// Queues an order for `server_name` unless it is not under a configured
+    /// suffix or was tried recently, or the hourly limit is reached. Returns
+    /// whether it was queued.
+    pub fn request(&self, server_name: &str) -> bool {
+        if !on_demand_name(&self.suffixes, server_name) {
+            return false;
+        }
+        let now = Instant::now();
+        if self
+            .attempts
+            .get(server_name)
+            .is_some_and(|at| now.duration_since(*at) < RETRY_INTERVAL)
+        {
+            return false;
+        }
+        let mut recent = self.recent.lock().unwrap();
+        while recent
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= Duration::from_secs(60 * 60))
+        {
+            recent.pop_front();
+        }
+        if recent.len() >= ON_DEMAND_PER_HOUR {
+            eprintln!(
+                "[ACME] Not ordering a certificate for {}: {} orders in the last hour",
+                server_name, ON_DEMAND_PER_HOUR
+            );
+            return false;
+        }
+        recent.push_back(now);
+        self.attempts.insert(server_name.to_string(), now);
+        self.queue.send(server_name.to_string()).is_ok()
+    }
+}
+
+/// Starts the task that issues the certificates `OnDemandIssuer::request` queues,
+/// reloading `resolver` after each one.
+pub fn spawn_on_demand(
+    config: AcmeConfig,
+    cert_dir: String,
+    resolver: Arc<CertDirResolver>,
+) -> Arc<OnDemandIssuer> {
+    let (queue, mut requests) = mpsc::unbounded_channel::<String>();
+    let suffixes = config.on_demand_suffixes.clone();
+    tokio::spawn(async move {
+        while let Some(domain) = requests.recv().await {
+            if !needs_renewal(&cert_path(&cert_dir, &domain)) {
+                continue;
+            }
+            let result = async {
+                let mut client = AcmeClient::new(&config, &cert_dir).await?;
+                issue_and_save(&mut client, &cert_dir, &domain).await?;
+                resolver.reload()
+            }
+            .await;
+            if let Err(e) = result {
+                eprintln!("[ACME] Failed to issue a certificate for {}: {}", domain, e);
+            }
+        }
+    });
+    Arc::new(OnDemandIssuer {
+        suffixes,
+        queue,
+        attempts: DashMap::new(),
+        recent: Mutex::new(VecDeque::new()),
+    })
+}
+
+/// Replaces `path` at once, so the resolver never reads half a file.
+fn save(path: &Path, pem: &str) -> anyhow::Result<()> {
+    let tmp = path.with_extension("pem.tmp");
+    let _ = fs::remove_file(&tmp);
+    write_private(&tmp.to_string_lossy(), pem.as_bytes())?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+/// A minimal RFC 8555 client: one account, ES256 signatures, HTTP-01 challenges.
+struct AcmeClient {
+    http: reqwest::Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    nonce: Option<String>,
+    /// The account URL, once the account exists.
+    kid: Option<String>,
+}
+
+impl AcmeClient {
+    async fn new(config: &AcmeConfig, cert_dir: &str) -> anyhow::Result<Self> {
+        install_default_crypto_provider();
+        let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(30));
+        if let Some(ca_file) = &config.ca_file {
+            builder =
+                builder.add_root_certificate(reqwest::Certificate::from_pem(&fs::read(ca_file)?)?);
+        }
+        let http = builder.build()?;
+        let directory = async {
+            http.get(&config.directory_url)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await
+        }
+        .await
+        .map_err(|e: reqwest::Error| {
+            anyhow::anyhow!(
+                "Failed to fetch ACME directory {}: {}",
+                config.directory_url,
+                e
+            )
+        })?;
+        let mut client = Self {
+            http,
+            directory,
+            key: account_key(cert_dir, &config.directory_url)?,
+            rng: SystemRandom::new(),
+            nonce: None,
+            kid: None,
+        };
+        let response = client
+            .post(
+                &client.directory.new_account.clone(),
+                Some(&json!({
+                    "termsOfServiceAgreed": true,
+                    "contact": config.contact,
+                })),
+            )
+            .await?;
+        client.kid = Some(location(&response)?);
+        Ok(client)
+    }
+
+    fn jwk(&self) -> Value {
+        // An uncompressed P-256 point: 0x04, x, y.
+        let point = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..65]),
+        })
+    }
+
+    /// RFC 7638 thumbprint of the account key. serde_json sorts the members,
+    /// which is the canonical form the RFC asks for.
+    fn thumbprint(&self) -> String {
+        let jwk = serde_json::to_vec(&self.jwk()).unwrap();
+        URL_SAFE_NO_PAD.encode(digest::digest(&digest::SHA256, &jwk))
+    }
+
+    /// A flattened JWS of `payload`, or of an empty payload for POST-as-GET.
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> anyhow::Result<Value> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk(),
+        }
+        let protected = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&protected)?);
+        let payload = match payload {
+            Some(payload) => URL_SAFE_NO_PAD.encode(serde_json::to_vec(payload)?),
+            None => String::new(),
+        };
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| anyhow::anyhow!("Failed to sign ACME request"))?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        }))
+    }
+
+    async fn nonce(&mut self) -> anyhow::Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let response = self.http.head(&self.directory.new_nonce).send().await?;
+        replay_nonce(&response).ok_or(anyhow::anyhow!("ACME server sent no nonce"))
+    }
+
+    /// Signs and POSTs `payload`, retrying when the server rejects the nonce.
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> anyhow::Result<reqwest::Response> {
+        let mut attempts = 0;
+        loop {
+            let nonce = self.nonce().await?;
+            let body = self.sign(url, &nonce, payload)?;
+            let response = self
+                .http
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/jose+json")
+                .body(serde_json::to_vec(&body)?)
+                .send()
+                .await?;
+            self.nonce = replay_nonce(&response);
+            if response.status().is_success() {
+                return Ok(response);
+            }
+            let status = response.status();
+            let problem: Problem = response.json().await.unwrap_or(Problem {
+                kind: String::new(),
+                detail: String::new(),
+            });
+            attempts += 1;
+            if problem.kind == "urn:ietf:params:acme:error:badNonce" && attempts < 3 {
+                continue;
+            }
+            return Err(anyhow::anyhow!(
+                "{} {}: {} {}",
+                url,
+                status,
+                problem.kind,
+                problem.detail
+            ));
+        }
+    }
+
+    async fn get(&mut self, url: &str) -> anyhow::Result<Value> {
+        Ok(self.post(url, None).await?.json().await?)
+    }
+
+    /// POST-as-GETs `url` until its status is no longer one of `pending`.
+    async fn poll(&mut self, url: &str, pending: &[&str]) -> anyhow::Result<Value> {
+        for _ in 0..POLL_ATTEMPTS {
+            let resource = self.get(url).await?;
+            if !pending.contains(&resource["status"].as_str().unwrap_or_default()) {
+                return Ok(resource);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(anyhow::anyhow!(
+            "{} still pending after {} polls",
+            url,
+            POLL_ATTEMPTS
+        ))
+    }
+
+    /// Orders a certificate for `domain` and returns its chain followed by its new key.
+    async fn issue(&mut self, domain: &str) -> anyhow::Result<String> {
+        if domain.starts_with("*.") {
+            return Err(anyhow::anyhow!(
+                "wildcard names need DNS-01, which is not supported"
+            ));
+        }
+        let response = self
+            .post(
+                &self.directory.new_order.clone(),
+                Some(&json!({ "identifiers": [{ "type": "dns", "value": domain }] })),
+            )
+            .await?;
+        let order_url = location(&response)?;
+        let order: Value = response.json().await?;
+        for authorization in order["authorizations"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default()
+        {
+            self.authorize(authorization.as_str().unwrap_or_default())
+                .await?;
+        }
+
+        let key = rcgen::KeyPair::generate()?;
+        let mut params = rcgen::CertificateParams::new(vec![domain.to_string()])?;
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, domain);
+        let csr = params.serialize_request(&key)?;
+        let finalize = order["finalize"]
+            .as_str()
+            .ok_or(anyhow::anyhow!("order has no finalize URL"))?;
+        self.post(
+            finalize,
+            Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr.der()) })),
+        )
+        .await?;
+        let order = self
+            .poll(&order_url, &["pending", "ready", "processing"])
+            .await?;
+        let certificate = match (order["status"].as_str(), order["certificate"].as_str()) {
+            (Some("valid"), Some(certificate)) => certificate.to_string(),
+            _ => return Err(anyhow::anyhow!("order failed: {}", order)),
+        };
+        let chain = self.post(&certificate, None).await?.text().await?;
+        Ok(format!("{}{}", chain.trim_end(), "\n") + &key.serialize_pem())
+    }
+
+    /// Answers the HTTP-01 challenge of an authorization and waits for it to be valid.
+    async fn authorize(&mut self, url: &str) -> anyhow::Result<()> {
+        let authorization = self.get(url).await?;
+        if authorization["status"] == "valid" {
+            return Ok(());
+        }
+        let challenge = authorization["challenges"]
+            .as_array()
+            .and_then(|challenges| challenges.iter().find(|c| c["type"] == "http-01"))
+            .ok_or(anyhow::anyhow!("no http-01 challenge offered"))?;
+        let (Some(token), Some(challenge_url)) =
+            (challenge["token"].as_str(), challenge["url"].as_str())
+        else {
+            return Err(anyhow::anyhow!("invalid http-01 challenge: {}", challenge));
+        };
+        HTTP01_CHALLENGES.insert(
+            token.to_string(),
+            format!("{}.{}", token, self.thumbprint()),
+        );
+        let result = async {
+            self.post(challenge_url, Some(&json!({}))).await?;
+            self.poll(url, &["pending"]).await
+        }
+        .await;
+        HTTP01_CHALLENGES.remove(token);
+        let authorization = result?;
+        if authorization["status"] != "valid" {
+            return Err(anyhow::anyhow!("authorization failed: {}", authorization));
+        }
+        Ok(())
+    }
+}
+
+/// The account key for `directory_url`, created on first use. Each directory
+/// gets its own key, so testing against Pebble does not touch the real account.
+fn account_key(cert_dir: &str, directory_url: &str) -> anyhow::Result<EcdsaKeyPair> {
+    let host = reqwest::Url::parse(directory_url)?
+        .host_str()
+        .unwrap_or("acme")
+        .to_string();
+    let path = Path::new(cert_dir)
+        .join("acme")
+        .join(format!("{}.key", host));
+    let path_str = path.to_string_lossy().to_string();
+    if !path.exists() {
+        fs::create_dir_all(path.parent().unwrap())?;
+        let key = rcgen::KeyPair::generate()?;
+        write_private(&path_str, key.serialize_pem().as_bytes())
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path_str, e))?;
+        println!("[ACME] Created account key {}", path_str);
+    }
+    let PrivateKeyDer::Pkcs8(key) = load_private_key(&path_str)? else {
+        return Err(anyhow::anyhow!("{} is not a PKCS#8 key", path_str));
+    };
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, key.secret_pkcs8_der())
+        .map_err(|e| anyhow::anyhow!("{} is not a P-256 key: {}", path_str, e))
+}
+
+fn replay_nonce(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get("Replay-Nonce")
+        .and_then(|nonce| nonce.to_str().ok())
+        .map(|nonce| nonce.to_string())
+}
+
+fn location(response: &reqwest::Response) -> anyhow::Result<String> {
+    response
+        .headers()
+        .get(reqwest::header::LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .map(|location| location.to_string())
+        .ok_or(anyhow::anyhow!("ACME server sent no Location"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::cert::load_cert;
+    use aws_lc_rs::signature::{ECDSA_P256_SHA256_FIXED, UnparsedPublicKey};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Just enough of an ACME directory for one order at a time. It validates
+    /// the HTTP-01 challenge by asking `http01_response` for the key
+    /// authorization, as it would ask the inbound, and signs the CSR it is sent.
+    #[derive(Default)]
+    struct MockDirectory {
+        base: String,
+        /// Thumbprint of the account key, from the `newAccount` request.
+        thumbprint: String,
+        domain: String,
+        authorized: bool,
+        chain: Option<String>,
+        /// Paths of the POSTs, in order.
+        log: Vec<String>,
+    }
+
+    async fn mock_directory() -> (String, Arc<Mutex<MockDirectory>>) {
+        use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Arc::new(
+            CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap(),
+        );
+        let state = Arc::new(Mutex::new(MockDirectory {
+            base: base.clone(),
+            ..Default::default()
+        }));
+        let shared = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve_mock(stream, shared.clone(), ca.clone()));
+            }
+        });
+        (format!("{}/dir", base), state)
+    }
+
+    async fn serve_mock(
+        mut stream: TcpStream,
+        state: Arc<Mutex<MockDirectory>>,
+        ca: Arc<rcgen::CertifiedIssuer<'static, rcgen::KeyPair>>,
+    ) {
+        let mut buf = Vec::new();
+        let (method, path, body) = loop {
+            let mut chunk = [0u8; 4096];
+            let n = stream.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "request cut short");
+            buf.extend_from_slice(&chunk[..n]);
+            let mut headers = [httparse::EMPTY_HEADER; 32];
+            let mut request = httparse::Request::new(&mut headers);
+            let httparse::Status::Complete(len) = request.parse(&buf).unwrap() else {
+                continue;
+            };
+            let content_length = request
+                .headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case("content-length"))
+                .map_or(0, |h| String::from_utf8_lossy(h.value).parse().unwrap());
+            if buf.len() >= len + content_length {
+                break (
+                    request.method.unwrap().to_string(),
+                    request.path.unwrap().to_string(),
+                    buf[len..len + content_length].to_vec(),
+                );
+            }
+        };
+        let (status, location, content_type, body) =
+            mock_reply(&mut state.lock().unwrap(), &ca, &method, &path, &body);
+        let mut response = format!(
+            "HTTP/1.1 {} X\r\nReplay-Nonce: n{}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            status,
+            buf.len(),
+            content_type,
+            body.len()
+        );
+        if let Some(location) = location {
+            response += &format!("Location: {}\r\n", location);
+        }
+        response += "\r\n";
+        stream.write_all(response.as_bytes()).await.unwrap();
+        stream.write_all(body.as_bytes()).await.unwrap();
+    }
+
+    fn mock_reply(
+        state: &mut MockDirectory,
+        ca: &rcgen::CertifiedIssuer<'static, rcgen::KeyPair>,
+        method: &str,
+        path: &str,
+        body: &[u8],
+    ) -> (u16, Option<String>, &'static str, String) {
+        let base = state.base.clone();
+        let json = |value: Value| (200, None, "application/json", value.to_string());
+        if method == "GET" {
+            return json(json!({
+                "newNonce": format!("{}/nonce", base),
+                "newAccount": format!("{}/account", base),
+                "newOrder": format!("{}/order", base),
+            }));
+        }
+        if method == "HEAD" {
+            return (200, None, "application/json", String::new());
+        }
+        let jws: Value = serde_json::from_slice(body).unwrap();
+        let decode = |part: &str| URL_SAFE_NO_PAD.decode(jws[part].as_str().unwrap()).unwrap();
+        let protected: Value = serde_json::from_slice(&decode("protected")).unwrap();
+        let payload = decode("payload");
+        let payload: Value = if payload.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_slice(&payload).unwrap()
+        };
+        state.log.push(path.to_string());
+        let order = |state: &MockDirectory| {
+            let mut order = json!({
+                "status": if state.chain.is_some() { "valid" } else { "pending" },
+                "authorizations": [format!("{}/authz", base)],
+                "finalize": format!("{}/finalize", base),
+            });
+            if state.chain.is_some() {
+                order["certificate"] = json!(format!("{}/cert", base));
+            }
+            order
+        };
+        match path {
+            "/account" => {
+                let jwk = serde_json::to_vec(&protected["jwk"]).unwrap();
+                state.thumbprint = URL_SAFE_NO_PAD.encode(digest::digest(&digest::SHA256, &jwk));
+                (
+                    201,
+                    Some(format!("{}/account/1", base)),
+                    "application/json",
+                    "{}".to_string(),
+                )
+            }
+            "/order" => {
+                state.domain = payload["identifiers"][0]["value"]
+                    .as_str()
+                    .unwrap()
+                    .to_string();
+                state.authorized = false;
+                state.chain = None;
+                let order = order(state);
+                (
+                    201,
+                    Some(format!("{}/order/1", base)),
+                    "application/json",
+                    order.to_string(),
+                )
+            }
+            "/authz" => json(json!({
+                "status": if state.authorized { "valid" } else { "pending" },
+                "identifier": { "type": "dns", "value": state.domain },
+                "challenges": [
+                    { "type": "dns-01", "url": format!("{}/dns", base), "token": "other" },
+                    { "type": "http-01", "url": format!("{}/challenge", base), "token": "mock-token" },
+                ],
+            })),
+            "/challenge" => {
+                let answer = http01_response("/.well-known/acme-challenge/mock-token");
+                state.authorized = answer == Some(format!("mock-token.{}", state.thumbprint));
+                json(json!({ "type": "http-01", "status": "processing" }))
+            }
+            "/finalize" => {
+                assert!(state.authorized, "finalized before the challenge was valid");
+                let csr = URL_SAFE_NO_PAD
+                    .decode(payload["csr"].as_str().unwrap())
+                    .unwrap();
+                let csr = rcgen::CertificateSigningRequestParams::from_der(&csr.into()).unwrap();
+                let leaf = csr.signed_by(ca).unwrap();
+                state.chain = Some(leaf.pem() + &ca.pem());
+                json(order(state))
+            }
+            "/order/1" => json(order(state)),
+            "/cert" => (
+                200,
+                None,
+                "application/pem-certificate-chain",
+                state.chain.clone().unwrap(),
+            ),
+            _ => (404, None, "application/problem+json", "{}".to_string()),
+        }
+    }
+
+    fn mock_config(directory_url: String) -> AcmeConfig {
+        AcmeConfig {
+            directory_url,
+            domains: vec!["static.tunnel.test".to_string()],
+            on_demand_suffixes: vec!["tunnel.test".to_string()],
+            contact: vec!["mailto:ops@tunnel.test".to_string()],
+            ca_file: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn orders_answers_the_challenge_and_saves_the_certificate() {
+        let (directory_url, state) = mock_directory().await;
+        let dir = std::env::temp_dir().join(format!("acme-flow-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cert_dir = dir.to_string_lossy().to_string();
+        let config = mock_config(directory_url);
+
+        assert_eq!(renew(&config, &cert_dir).await.unwrap(), 1);
+        assert_eq!(
+            state.lock().unwrap().log,
+            [
+                "/account",
+                "/order",
+                "/authz",
+                "/challenge",
+                "/authz",
+                "/finalize",
+                "/order/1",
+                "/cert"
+            ]
+        );
+        let path = cert_path(&cert_dir, "static.tunnel.test");
+        let pem = path.to_string_lossy().to_string();
+        let (chain, _) = load_cert(pem.clone(), pem).unwrap();
+        assert_eq!(chain.len(), 2);
+        assert!(!needs_renewal(&path));
+        assert!(!HTTP01_CHALLENGES.contains_key("mock-token"));
+        // Nothing is due any more.
+        assert_eq!(renew(&config, &cert_dir).await.unwrap(), 0);
+
+        // A tunnel hostname is ordered when asked for, and renewed afterwards.
+        let resolver = CertDirResolver::load(&cert_dir).unwrap();
+        let on_demand = spawn_on_demand(config.clone(), cert_dir.clone(), resolver.clone());
+        assert!(on_demand.request("web.tunnel.test"));
+        assert!(!on_demand.request("web.tunnel.test"));
+        for _ in 0..100 {
+            if resolver.find("web.tunnel.test").is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(resolver.find("web.tunnel.test").is_some());
+        assert_eq!(state.lock().unwrap().domain, "web.tunnel.test");
+        assert_eq!(
+            issued_on_demand(&config, &cert_dir),
+            vec!["web.tunnel.test"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn on_demand_orders_are_limited() {
+        let suffixes = vec!["tunnel.test".to_string()];
+        assert!(on_demand_name(&suffixes, "web.tunnel.test"));
+        assert!(on_demand_name(&suffixes, "my-app1.tunnel.test"));
+        assert!(!on_demand_name(&suffixes, "tunnel.test"));
+        assert!(!on_demand_name(&suffixes, "a.b.tunnel.test"));
+        assert!(!on_demand_name(&suffixes, "webtunnel.test"));
+        assert!(!on_demand_name(&suffixes, "-web.tunnel.test"));
+        assert!(!on_demand_name(&suffixes, "web.example.com"));
+
+        let (queue, mut queued) = mpsc::unbounded_channel();
+        let issuer = OnDemandIssuer {
+            suffixes,
+            queue,
+            attempts: DashMap::new(),
+            recent: Mutex::new(VecDeque::new()),
+        };
+        assert!(!issuer.request("web.example.com"));
+        for i in 0..ON_DEMAND_PER_HOUR {
+            assert!(issuer.request(&format!("app{}.tunnel.test", i)));
+        }
+        assert!(!issuer.request("one-more.tunnel.test"));
+        queued.close();
+        let mut count = 0;
+        while queued.recv().await.is_some() {
+            count += 1;
+        }
+        assert_eq!(count, ON_DEMAND_PER_HOUR);
+    }
+
+    #[test]
+    fn requests_are_signed_with_the_account_key() {
+        let dir = std::env::temp_dir().join(format!("acme-{}", std::process::id()));
+        let cert_dir = dir.to_string_lossy().to_string();
+        let key = account_key(&cert_dir, "https://acme.test/directory").unwrap();
+        let mut client = AcmeClient {
+            http: reqwest::Client::new(),
+            directory: Directory {
+                new_nonce: String::new(),
+                new_account: String::new(),
+                new_order: String::new(),
+            },
+            key,
+            rng: SystemRandom::new(),
+            nonce: None,
+            kid: None,
+        };
+        // The key is kept, so the account survives restarts.
+        let again = account_key(&cert_dir, "https://acme.test/directory").unwrap();
+        assert_eq!(
+            again.public_key().as_ref(),
+            client.key.public_key().as_ref()
+        );
+
+        let jws = client
+            .sign("https://acme.test/new-account", "n1", Some(&json!({})))
+            .unwrap();
+        let protected: Value = serde_json::from_slice(
+            &URL_SAFE_NO_PAD
+                .decode(jws["protected"].as_str().unwrap())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(protected["jwk"], client.jwk());
+        let signing_input = format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap(),
+            jws["payload"].as_str().unwrap()
+        );
+        let signature = URL_SAFE_NO_PAD
+            .decode(jws["signature"].as_str().unwrap())
+            .unwrap();
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, client.key.public_key().as_ref())
+            .verify(signing_input.as_bytes(), &signature)
+            .unwrap();
+
+        client.kid = Some("https://acme.test/acct/1".to_string());
+        let jws = client
+            .sign("https://acme.test/order/1", "n2", None)
+            .unwrap();
+        assert_eq!(jws["payload"], "");
+        assert!(
+            !URL_SAFE_NO_PAD
+                .decode(jws["protected"].as_str().unwrap())
+                .map(|p| String::from_utf8_lossy(&p).contains("jwk"))
+                .unwrap()
+        );
+        assert_eq!(client.thumbprint().len(), 43);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn renews_missing_and_expiring_certificates() {
+        let dir = std::env::temp_dir().join(format!("acme-renew-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params =
+            rcgen::CertificateParams::new(vec!["web.tunnel.test".to_string()]).unwrap();
+        fs::write(
+            dir.join("current.pem"),
+            params.self_signed(&key).unwrap().pem(),
+        )
+        .unwrap();
+        params.not_after = rcgen::date_time_ymd(2000, 1, 1);
+        fs::write(
+            dir.join("expired.pem"),
+            params.self_signed(&key).unwrap().pem(),
+        )
+        .unwrap();
+
+        assert!(!needs_renewal(&dir.join("current.pem")));
+        assert!(needs_renewal(&dir.join("expired.pem")));
+        assert!(needs_renewal(&dir.join("missing.pem")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn answers_only_pending_challenges() {
+        HTTP01_CHALLENGES.insert("tok".to_string(), "tok.thumb".to_string());
+        assert_eq!(
+            http01_response("/.well-known/acme-challenge/tok").as_deref(),
+            Some("tok.thumb")
+        );
+        assert_eq!(http01_response("/.well-known/acme-challenge/other"), None);
+        assert_eq!(http01_response("/tok"), None);
+        HTTP01_CHALLENGES.remove("tok");
+    }
+}
@@ -0,0 +1,280 @@
+use aws_lc_rs::constant_time::verify_slices_are_equal;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::tunnel::inbound::json_response;
+use crate::tunnel::payload::{Drain, Kick, Message, Reconfigure};
+use crate::tunnel::session::{TRANSPORT_SESSION_MAP, get_session};
+use crate::tunnel::supernode::{kick_session, push_command, revoke, revoked_ids, unrevoke};
+
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+pub struct AdminConfig {
+    pub admin_addr: String,
+    /// When set, requests must carry `Authorization: Bearer <token>`. Required
+    /// unless `admin_addr` only resolves to loopback addresses.
+    pub admin_token: Option<String>,
+}
+
+impl AdminConfig {
+    /// Refuses to expose the unauthenticated API beyond the local host.
+    pub async fn check(&self) -> anyhow::Result<()> {
+        if self.admin_token.is_some() {
+            return Ok(());
+        }
+        for addr in tokio::net::lookup_host(&self.admin_addr).await? {
+            if !addr.ip().is_loopback() {
+                return Err(anyhow::anyhow!(
+                    "--admin-token is required when the admin API listens on {} (not a loopback address)",
+                    self.admin_addr
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn authorized(&self, authorization: Option<&str>) -> bool {
+        let Some(token) = &self.admin_token else {
+            return true;
+        };
+        let expected = format!("Bearer {}", token);
+        authorization.is_some_and(|value| {
+            verify_slices_are_equal(value.as_bytes(), expected.as_bytes()).is_ok()
+        })
+    }
+}
+
+struct AdminRequest {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+/// Small HTTP API for operators: list sessions and push control commands to edges.
+pub async fn start_admin(config: AdminConfig) -> anyhow::Result<()> {
+    config.check().await?;
+    let listener = TcpListener::bind(&config.admin_addr).await?;
+    println!("[Admin] Listening on {}", config.admin_addr);
+    let config = Arc::new(config);
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve(stream, &config).await {
+                eprintln!("[Admin] Request from {} failed: {:?}", addr, e);
+            }
+        });
+    }
+}
+
+async fn serve(mut stream: TcpStream, config: &AdminConfig) -> anyhow::Result<()> {
+    let request = read_request(&mut stream).await?;
+    let (status, body) = if config.authorized(request.authorization.as_deref()) {
+        println!("[Admin] {} {}", request.method, request.path);
+        route(&request)
+    } else {
+        (401, json!({ "code": 401, "message": "unauthorized" }))
+    };
+    json_response(&mut stream, status, &body).await
+}
+
+async fn read_request(stream: &mut TcpStream) -> anyhow::Result<AdminRequest> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(anyhow::anyhow!(
+                "Connection closed before request was complete"
+            ));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() > MAX_REQUEST_LEN {
+            return Err(anyhow::anyhow!("Request too large"));
+        }
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut request = httparse::Request::new(&mut headers);
+        let httparse::Status::Complete(header_len) = request.parse(&buf)? else {
+            continue;
+        };
+        let header = |name: &str| {
+            request
+                .headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case(name))
+                .map(|h| String::from_utf8_lossy(h.value).to_string())
+        };
+        let content_length: usize = header("content-length")
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(0);
+        if buf.len() < header_len + content_length {
+            continue;
+        }
+        let path = request.path.unwrap_or("/");
+        return Ok(AdminRequest {
+            method: request.method.unwrap_or("GET").to_string(),
+            path: path.split('?').next().unwrap_or(path).to_string(),
+            authorization: header("authorization"),
+            body: buf[header_len..header_len + content_length].to_vec(),
+        });
+    }
+}
+
+fn route(request: &AdminRequest) -> (u16, Value) {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let result = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["sessions"]) => Ok(list_sessions()),
+        ("POST", ["sessions", id, action]) => {
+            if get_session(id).is_none() {
+                return (
+                    404,
+                    json!({ "code": 404, "message": format!("session [{}] not found", id) }),
+                );
+            }
+            session_action(id, action, &request.body)
+        }
+        ("POST", ["message"]) => parse_body::<Message>(&request.body).map(broadcast_message),
+        ("GET", ["revocations"]) => Ok(list_revocations()),
+        ("POST", ["revocations"]) => {
+            parse_body::<Revocation>(&request.body).and_then(add_revocation)
+        }
+        ("DELETE", ["revocations"]) => {
+            parse_body::<Revocation>(&request.body).and_then(lift_revocation)
+        }
+        _ => return (404, json!({ "code": 404, "message": "not found" })),
+    };
+    match result {
+        Ok(body) => (200, body),
+        Err(e) => (400, json!({ "code": 400, "message": e.to_string() })),
+    }
+}
+
+fn list_sessions() -> Value {
+    let sessions: Vec<Value> = TRANSPORT_SESSION_MAP
+        .iter()
+        .map(|session| {
+            json!({
+                "id": session.key(),
+                "tunnels": session.tunnels,
+                "limits": session.limits,
+                "active_streams": session.active_streams.load(Ordering::Relaxed),
+                "meta": session.meta,
+                "version": session.protocol.version,
+                "capabilities": session.protocol.capabilities,
+                "control": session.control.is_some(),
+                "idle_secs": session.ping_at.elapsed().as_secs(),
+            })
+        })
+        .collect();
+    json!({ "code": 200, "sessions": sessions })
+}
+
+fn session_action(id: &str, action: &str, body: &[u8]) -> anyhow::Result<Value> {
+    match action {
+        "kick" => kick_session(id, &parse_body::<Kick>(body)?)?,
+        "drain" => push_command(id, &parse_body::<Drain>(body)?)?,
+        "reconfigure" => push_command(id, &parse_body::<Reconfigure>(body)?)?,
+        "message" => push_command(id, &parse_body::<Message>(body)?)?,
+        _ => return Err(anyhow::anyhow!("Unknown action {}", action)),
+    }
+    Ok(json!({ "code": 200, "message": "ok" }))
+}
+
+fn broadcast_message(message: Message) -> Value {
+    let ids: Vec<String> = TRANSPORT_SESSION_MAP
+        .iter()
+        .map(|session| session.key().clone())
+        .collect();
+    let sent = ids
+        .iter()
+        .filter(|id| push_command(id, &message).is_ok())
+        .count();
+    json!({ "code": 200, "sent": sent })
+}
+
+/// Body of `/revocations`: the token (by its `token_id`) and/or client id to
+/// refuse from now on.
+#[derive(Debug, Default, Deserialize)]
+struct Revocation {
+    token_id: Option<String>,
+    client_id: Option<String>,
+    reason: Option<String>,
+}
+
+impl Revocation {
+    fn check(&self) -> anyhow::Result<()> {
+        if self.token_id.is_none() && self.client_id.is_none() {
+            return Err(anyhow::anyhow!("token_id or client_id is required"));
+        }
+        Ok(())
+    }
+}
+
+fn list_revocations() -> Value {
+    let (token_ids, client_ids) = revoked_ids();
+    json!({ "code": 200, "token_ids": token_ids, "client_ids": client_ids })
+}
+
+fn add_revocation(revocation: Revocation) -> anyhow::Result<Value> {
+    revocation.check()?;
+    let closed = revoke(
+        revocation.token_id.as_deref(),
+        revocation.client_id.as_deref(),
+        revocation.reason.as_deref().unwrap_or("revoked"),
+    );
+    Ok(json!({ "code": 200, "closed": closed }))
+}
+
+fn lift_revocation(revocation: Revocation) -> anyhow::Result<Value> {
+    revocation.check()?;
+    unrevoke(
+        revocation.token_id.as_deref(),
+        revocation.client_id.as_deref(),
+    );
+    Ok(json!({ "code": 200, "message": "ok" }))
+}
+
+/// An empty body means a payload with every field at its default.
+fn parse_body<T: DeserializeOwned + Default>(body: &[u8]) -> anyhow::Result<T> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(T::default());
+    }
+    Ok(serde_json::from_slice(body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(admin_addr: &str, admin_token: Option<&str>) -> AdminConfig {
+        AdminConfig {
+            admin_addr: admin_addr.to_string(),
+            admin_token: admin_token.map(|v| v.to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn only_loopback_may_skip_the_token() {
+        assert!(config("127.0.0.1:9000", None).check().await.is_ok());
+        assert!(config("[::1]:9000", None).check().await.is_ok());
+        assert!(config("0.0.0.0:9000", None).check().await.is_err());
+        assert!(config("192.0.2.1:9000", None).check().await.is_err());
+        assert!(config("0.0.0.0:9000", Some("secret")).check().await.is_ok());
+    }
+
+    #[test]
+    fn token_must_match_exactly() {
+        let config = config("0.0.0.0:9000", Some("secret"));
+        assert!(config.authorized(Some("Bearer secret")));
+        assert!(!config.authorized(Some("Bearer secreT")));
+        assert!(!config.authorized(Some("Bearer secret2")));
+        assert!(!config.authorized(Some("secret")));
+        assert!(!config.authorized(None));
+    }
+}
@@ -0,0 +1,252 @@
+use crate::tunnel::egress::EgressPolicy;
+use crate::tunnel::packet::TunnelMeta;
+use crate::tunnel::token::{TokenGrant, TokenLimits};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::time::Instant;
+
+/// Time an external authorizer gets to answer before the `Auth` is refused.
+pub const AUTHORIZER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the supernode tells an external authorizer about an `Auth`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AuthorizeRequest {
+    /// Only sent by edges using legacy auth; otherwise the authorizer has to
+    /// return the token of `token_id` so the edge's proof can be checked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    pub token_id: String,
+    pub remote_addr: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub meta: TunnelMeta,
+}
+
+/// The authorizer's decision: `{"allow": true, "client_id": "...", "tunnels": [...],
+/// "limits": {...}, "egress": {...}, "token": "..."}` or `{"allow": false, "reason": "..."}`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AuthorizeResponse {
+    pub allow: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// The token of the requested `token_id`, needed to check a challenge proof.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub tunnels: Vec<String>,
+    #[serde(default)]
+    pub limits: TokenLimits,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub egress: Option<EgressPolicy>,
+}
+
+impl AuthorizeResponse {
+    /// The grant of an allowing response; a denial becomes an error with its reason.
+    pub fn grant(&self) -> anyhow::Result<TokenGrant> {
+        if !self.allow {
+            return Err(anyhow::anyhow!(
+                "{}",
+                self.reason.as_deref().unwrap_or("denied by authorizer")
+            ));
+        }
+        Ok(TokenGrant {
+            client_id: self
+                .client_id
+                .clone()
+                .ok_or(anyhow::anyhow!("authorizer allowed without a client_id"))?,
+            tunnels: self.tunnels.clone(),
+            limits: self.limits.clone(),
+            egress: self.egress.clone(),
+        })
+    }
+}
+
+/// Where the decisions come from.
+#[derive(Debug, Clone)]
+pub enum AuthorizerBackend {
+    /// The request is POSTed as JSON, the response body is the decision.
+    Http(String),
+    /// The program is run with the request as JSON on stdin and prints the decision on stdout.
+    Exec(String),
+}
+
+/// Delegates `Auth` decisions to an account system the supernode cannot sync
+/// tokens from. Decisions, denials included, are cached for `ttl`.
+pub struct ExternalAuthorizer {
+    backend: AuthorizerBackend,
+    ttl: Duration,
+    client: reqwest::Client,
+    cache: DashMap<String, (Instant, AuthorizeResponse)>,
+}
+
+impl ExternalAuthorizer {
+    pub fn new(backend: AuthorizerBackend, ttl: Duration) -> anyhow::Result<Self> {
+        crate::transport::cert::install_default_crypto_provider();
+        Ok(Self {
+            backend,
+            ttl,
+            client: reqwest::Client::builder()
+                .timeout(AUTHORIZER_TIMEOUT)
+                .build()?,
+            cache: DashMap::new(),
+        })
+    }
+
+    pub async fn authorize(&self, request: &AuthorizeRequest) -> anyhow::Result<AuthorizeResponse> {
+        let key = cache_key(request)?;
+        if let Some(entry) = self.cache.get(&key)
+            && entry.0 > Instant::now()
+        {
+            return Ok(entry.1.clone());
+        }
+        let response = match &self.backend {
+            AuthorizerBackend::Http(url) => self.call_http(url, request).await,
+            AuthorizerBackend::Exec(program) => call_exec(program, request).await,
+        }
+        .map_err(|e| anyhow::anyhow!("authorizer failed: {}", e))?;
+        if !self.ttl.is_zero() {
+            let now = Instant::now();
+            self.cache.retain(|_, (expires, _)| *expires > now);
+            self.cache.insert(key, (now + self.ttl, response.clone()));
+        }
+        Ok(response)
+    }
+
+    async fn call_http(
+        &self,
+        url: &str,
+        request: &AuthorizeRequest,
+    ) -> anyhow::Result<AuthorizeResponse> {
+        Ok(self
+            .client
+            .post(url)
+            .json(request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+}
+
+/// Reconnects come from a new source port, so decisions are cached per remote IP.
+fn cache_key(request: &AuthorizeRequest) -> anyhow::Result<String> {
+    let mut request = request.clone();
+    if let Ok(addr) = request.remote_addr.parse::<SocketAddr>() {
+        request.remote_addr = addr.ip().to_string();
+    }
+    Ok(serde_json::to_string(&request)?)
+}
+
+async fn call_exec(program: &str, request: &AuthorizeRequest) -> anyhow::Result<AuthorizeResponse> {
+    let mut child = tokio::process::Command::new(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    // Programs that decide without reading the request may exit before it is written.
+    let _ = stdin.write_all(&serde_json::to_vec(request)?).await;
+    drop(stdin);
+    let output = tokio::time::timeout(AUTHORIZER_TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| anyhow::anyhow!("{} timed out", program))??;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("{} exited with {}", program, output.status));
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+static EXTERNAL_AUTHORIZER: LazyLock<RwLock<Option<Arc<ExternalAuthorizer>>>> =
+    LazyLock::new(Default::default);
+
+/// Sets the authorizer asked about tokens the token store does not know.
+pub fn set_external_authorizer(authorizer: Option<Arc<ExternalAuthorizer>>) {
+    *EXTERNAL_AUTHORIZER.write().unwrap() = authorizer;
+}
+
+pub fn external_authorizer() -> Option<Arc<ExternalAuthorizer>> {
+    EXTERNAL_AUTHORIZER.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(name: &str, body: &str) -> String {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[tokio::test]
+    async fn exec_authorizer_decides_and_caches() {
+        // Every script is written before any is run, so none is busy being written.
+        let calls = std::env::temp_dir().join(format!("authorizer-calls-{}", std::process::id()));
+        let allow = script(
+            "authorizer-allow",
+            &format!(
+                "cat > /dev/null; echo x >> {}; echo '{{\"allow\": true, \"client_id\": \"laptop\", \"limits\": {{\"max_streams\": 2}}}}'",
+                calls.display()
+            ),
+        );
+        let deny = script(
+            "authorizer-deny",
+            "echo '{\"allow\": false, \"reason\": \"account suspended\"}'",
+        );
+        let fail = script("authorizer-fail", "exit 3");
+        let request = AuthorizeRequest {
+            token: Some("s3cret".to_string()),
+            token_id: "sha256:x".to_string(),
+            remote_addr: "192.0.2.1:5000".to_string(),
+            ..Default::default()
+        };
+
+        let authorizer = ExternalAuthorizer::new(
+            AuthorizerBackend::Exec(allow.clone()),
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        for port in [5000, 5001] {
+            let request = AuthorizeRequest {
+                remote_addr: format!("192.0.2.1:{}", port),
+                ..request.clone()
+            };
+            let grant = authorizer
+                .authorize(&request)
+                .await
+                .unwrap()
+                .grant()
+                .unwrap();
+            assert_eq!(grant.client_id, "laptop");
+            assert_eq!(grant.limits.max_streams, Some(2));
+        }
+        assert_eq!(std::fs::read_to_string(&calls).unwrap().lines().count(), 1);
+
+        let authorizer =
+            ExternalAuthorizer::new(AuthorizerBackend::Exec(deny.clone()), Duration::ZERO).unwrap();
+        let response = authorizer.authorize(&request).await.unwrap();
+        assert_eq!(
+            response.grant().unwrap_err().to_string(),
+            "account suspended"
+        );
+
+        let authorizer =
+            ExternalAuthorizer::new(AuthorizerBackend::Exec(fail.clone()), Duration::ZERO).unwrap();
+        assert!(authorizer.authorize(&request).await.is_err());
+
+        for path in [calls.to_string_lossy().to_string(), allow, deny, fail] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::tunnel::common::{DEFAULT_MAX_DATA_LEN, HEADER_FIXED_LEN, MAX_DATA_LEN};
+use crate::tunnel::error::ProtocolError;
+use crate::tunnel::packet::{MetaEncoding, TunnelCommand, TunnelCommandPacket};
+
+/// High bit of the command byte; set when the meta is CBOR instead of JSON.
+const CBOR_FLAG: u8 = 0x80;
+
+static MAX_DATA_LEN_LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_DATA_LEN);
+
+/// Sets the largest meta this process accepts and advertises to peers.
+pub fn set_max_data_len(len: usize) {
+    MAX_DATA_LEN_LIMIT.store(len.max(MAX_DATA_LEN), Ordering::Relaxed);
+}
+
+pub fn max_data_len() -> usize {
+    MAX_DATA_LEN_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Wire format: `flags|command: u8 | length: u32 (big endian) | meta: [u8; length]`.
+#[derive(Debug, Clone)]
+pub struct TunnelCodec {
+    max_data_len: usize,
+}
+
+impl Default for TunnelCodec {
+    fn default() -> Self {
+        Self {
+            max_data_len: max_data_len(),
+        }
+    }
+}
+
+impl TunnelCodec {
+    /// Reads a single packet without consuming anything past its end, so the
+    /// stream can be handed over to a relay afterwards.
+    pub async fn read_packet<R>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<TunnelCommandPacket, ProtocolError>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        let mut buf = BytesMut::with_capacity(HEADER_FIXED_LEN);
+        loop {
+            if let Some(packet) = self.decode(&mut buf)? {
+                return Ok(packet);
+            }
+            let start = buf.len();
+            buf.resize(start + self.missing_bytes(&buf), 0);
+            reader.read_exact(&mut buf[start..]).await?;
+        }
+    }
+
+    fn missing_bytes(&self, buf: &BytesMut) -> usize {
+        if buf.len() < HEADER_FIXED_LEN {
+            return HEADER_FIXED_LEN - buf.len();
+        }
+        HEADER_FIXED_LEN + Self::data_len(buf) - buf.len()
+    }
+
+    fn data_len(buf: &[u8]) -> usize {
+        u32::from_be_bytes(buf[1..HEADER_FIXED_LEN].try_into().unwrap()) as usize
+    }
+}
+
+impl Decoder for TunnelCodec {
+    type Item = TunnelCommandPacket;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < HEADER_FIXED_LEN {
+            return Ok(None);
+        }
+        let command = TunnelCommand::from_u8(src[0] & !CBOR_FLAG)
+            .ok_or(ProtocolError::InvalidCommand(src[0]))?;
+        let encoding = if src[0] & CBOR_FLAG != 0 {
+            MetaEncoding::Cbor
+        } else {
+            MetaEncoding::Json
+        };
+        let length = Self::data_len(src);
+        if length > self.max_data_len {
+            return Err(ProtocolError::DataTooLarge(length));
+        }
+        if src.len() < HEADER_FIXED_LEN + length {
+            src.reserve(HEADER_FIXED_LEN + length - src.len());
+            return Ok(None);
+        }
+        src.advance(HEADER_FIXED_LEN);
+        let data = src.split_to(length);
+        Ok(Some(TunnelCommandPacket {
+            command,
+            length: length as u32,
+            meta: TunnelCommandPacket::decode_meta(&data, encoding)?,
+            encoding,
+        }))
+    }
+}
+
+impl Encoder<&TunnelCommandPacket> for TunnelCodec {
+    type Error = ProtocolError;
+
+    fn encode(
+        &mut self,
+        item: &TunnelCommandPacket,
+        dst: &mut BytesMut,
+    ) -> Result<(), Self::Error> {
+        let data = TunnelCommandPacket::encode_meta(&item.meta, item.encoding);
+        if data.len() > self.max_data_len {
+            return Err(ProtocolError::DataTooLarge(data.len()));
+        }
+        let flags = match item.encoding {
+            MetaEncoding::Json => 0,
+            MetaEncoding::Cbor => CBOR_FLAG,
+        };
+        dst.reserve(HEADER_FIXED_LEN + data.len());
+        dst.put_u8(flags | item.command as u8);
+        dst.put_u32(data.len() as u32);
+        dst.extend_from_slice(&data);
+        Ok(())
+    }
+}
+
+impl Encoder<TunnelCommandPacket> for TunnelCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, item: TunnelCommandPacket, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode(&item, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::packet::TunnelMeta;
+    use serde_json::Value;
+
+    const ALL_COMMANDS: [TunnelCommand; 14] = [
+        TunnelCommand::Ping,
+        TunnelCommand::Pong,
+        TunnelCommand::Auth,
+        TunnelCommand::AuthResult,
+        TunnelCommand::Forward,
+        TunnelCommand::SetSessionMeta,
+        TunnelCommand::Error,
+        TunnelCommand::SetSessionMetaResult,
+        TunnelCommand::ForwardResult,
+        TunnelCommand::Kick,
+        TunnelCommand::Drain,
+        TunnelCommand::Reconfigure,
+        TunnelCommand::Message,
+        TunnelCommand::AuthChallenge,
+    ];
+
+    fn sample_meta() -> TunnelMeta {
+        TunnelMeta::from([("k".to_string(), Value::from("v"))])
+    }
+
+    #[test]
+    fn round_trip_every_command() {
+        for command in ALL_COMMANDS {
+            let packet = TunnelCommandPacket::new(command, &sample_meta());
+            let mut buf = BytesMut::new();
+            TunnelCodec::default().encode(&packet, &mut buf).unwrap();
+            let decoded = TunnelCodec::default().decode(&mut buf).unwrap().unwrap();
+            assert_eq!(decoded.command, command);
+            assert_eq!(decoded.length, packet.length);
+            assert_eq!(decoded.meta, packet.meta);
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn golden_bytes_every_command() {
+        for (byte, command) in ALL_COMMANDS.into_iter().enumerate() {
+            let packet = TunnelCommandPacket::new(command, &sample_meta());
+            let mut expected = vec![byte as u8, 0, 0, 0, 9];
+            expected.extend_from_slice(br#"{"k":"v"}"#);
+            assert_eq!(packet.to_bytes().unwrap(), expected, "{:?}", command);
+        }
+        let empty = TunnelCommandPacket::new(TunnelCommand::Ping, &TunnelMeta::new());
+        assert_eq!(empty.to_bytes().unwrap(), vec![0, 0, 0, 0, 2, b'{', b'}']);
+    }
+
+    #[test]
+    fn round_trip_cbor() {
+        for command in ALL_COMMANDS {
+            let packet =
+                TunnelCommandPacket::new(command, &sample_meta()).with_encoding(MetaEncoding::Cbor);
+            let mut buf = BytesMut::new();
+            TunnelCodec::default().encode(&packet, &mut buf).unwrap();
+            let decoded = TunnelCodec::default().decode(&mut buf).unwrap().unwrap();
+            assert_eq!(decoded.command, command);
+            assert_eq!(decoded.encoding, MetaEncoding::Cbor);
+            assert_eq!(decoded.meta, packet.meta);
+        }
+    }
+
+    #[test]
+    fn golden_bytes_cbor() {
+        let packet = TunnelCommandPacket::new(TunnelCommand::Forward, &sample_meta())
+            .with_encoding(MetaEncoding::Cbor);
+        assert_eq!(
+            packet.to_bytes().unwrap(),
+            vec![0x84, 0, 0, 0, 5, 0xa1, 0x61, b'k', 0x61, b'v']
+        );
+    }
+
+    #[test]
+    fn decode_waits_for_complete_frame() {
+        let bytes = TunnelCommandPacket::new(TunnelCommand::Forward, &sample_meta())
+            .to_bytes()
+            .unwrap();
+        let mut codec = TunnelCodec::default();
+        let mut buf = BytesMut::new();
+        for byte in &bytes[..bytes.len() - 1] {
+            buf.put_u8(*byte);
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+        }
+        buf.put_u8(bytes[bytes.len() - 1]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn decode_rejects_unknown_command_and_oversized_length() {
+        let mut buf = BytesMut::from(&[0xff, 0, 0, 0, 2][..]);
+        assert!(matches!(
+            TunnelCodec::default().decode(&mut buf),
+            Err(ProtocolError::InvalidCommand(0xff))
+        ));
+        let mut buf = BytesMut::new();
+        buf.put_u8(TunnelCommand::Ping as u8);
+        buf.put_u32(DEFAULT_MAX_DATA_LEN as u32 + 1);
+        assert!(matches!(
+            TunnelCodec::default().decode(&mut buf),
+            Err(ProtocolError::DataTooLarge(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversized_meta_is_an_error_not_a_panic() {
+        let mut meta = TunnelMeta::new();
+        meta.insert(
+            "k".to_string(),
+            Value::String("x".repeat(DEFAULT_MAX_DATA_LEN)),
+        );
+        let packet = TunnelCommandPacket::new(TunnelCommand::SetSessionMetaResult, &meta);
+        assert!(matches!(
+            packet.to_bytes(),
+            Err(ProtocolError::DataTooLarge(_))
+        ));
+        let mut written = Vec::new();
+        assert!(packet.write_to(&mut written).await.is_err());
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn decode_rejects_malformed_meta() {
+        let mut buf =
+            BytesMut::from(&[TunnelCommand::Ping as u8, 0, 0, 0, 3, b'n', b'o', b'!'][..]);
+        assert!(matches!(
+            TunnelCodec::default().decode(&mut buf),
+            Err(ProtocolError::InvalidMeta(_))
+        ));
+        let mut buf = BytesMut::from(&[TunnelCommand::Ping as u8, 0, 0, 0, 2, b'[', b']'][..]);
+        assert!(matches!(
+            TunnelCodec::default().decode(&mut buf),
+            Err(ProtocolError::InvalidMeta(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn read_packet_leaves_trailing_bytes() {
+        let mut bytes = TunnelCommandPacket::new(TunnelCommand::Forward, &sample_meta())
+            .to_bytes()
+            .unwrap();
+        bytes.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        let mut reader = bytes.as_slice();
+        let packet = TunnelCommandPacket::read_from(&mut reader).await.unwrap();
+        assert_eq!(packet.command, TunnelCommand::Forward);
+        assert_eq!(reader, b"GET / HTTP/1.1\r\n");
+    }
+}
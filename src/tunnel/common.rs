@@ -2,9 +2,38 @@ pub const FORWARD_TO_KEY: &str = "X-Tunnel-Forward-To";
 pub const AUTH_TOKEN_KEY: &str = "X-Tunnel-Token";
 pub const DEVICE_NAME_KEY: &str = "device_name";
 pub const HEADER_FIXED_LEN: usize = 5;
+/// Meta limit of peers that predate `X-Tunnel-Max-Data-Len`.
 pub const MAX_DATA_LEN: usize = 1024;
+pub const DEFAULT_MAX_DATA_LEN: usize = 64 * 1024;
 pub const MAX_SNIFF_LEN: usize = 2048;
+pub const FORWARD_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+pub const FORWARD_RESULT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
+pub const COMMAND_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/// QUIC application close code used when the control stream of a connection is lost.
+pub const CLOSE_CONTROL_LOST: u32 = 1;
+/// The edge was kicked by an operator.
+pub const CLOSE_KICKED: u32 = 2;
+/// The edge is moving to another supernode.
+pub const CLOSE_DRAINED: u32 = 3;
+/// The edge's token or client id was revoked; it must not reconnect.
+pub const CLOSE_REVOKED: u32 = 4;
+/// Another edge registered the same client id and took over its tunnels; the
+/// edge must not reconnect, or the two would keep replacing each other.
+pub const CLOSE_REPLACED: u32 = 5;
 
 pub fn get_client_id_from_token(token: &str) -> String {
     token.to_string()
 }
+
+pub fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub fn new_trace_id() -> String {
+    static TRACE_SEQ: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let seq = TRACE_SEQ.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    format!("{:x}-{:x}", unix_millis(), seq)
+}
@@ -0,0 +1,189 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::{SinkExt, StreamExt};
+use tokio::sync::{Notify, mpsc, oneshot};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::transport::base::{TransportRecvStream, TransportSendStream};
+use crate::tunnel::codec::TunnelCodec;
+use crate::tunnel::packet::TunnelCommandPacket;
+
+/// Long-lived command stream of a connection, opened by the edge right after
+/// `Auth`. Either side can send requests and one-way commands on it; responses
+/// are matched to requests in order, since each side answers requests in the
+/// order it received them.
+pub struct ControlChannel {
+    sender: mpsc::UnboundedSender<TunnelCommandPacket>,
+    pending: Mutex<VecDeque<oneshot::Sender<TunnelCommandPacket>>>,
+    closed: AtomicBool,
+    closed_notify: Notify,
+}
+
+impl ControlChannel {
+    /// Takes over both halves of a stream and calls `handler` for every packet that is not a
+    /// response to one of our requests. A returned packet is sent back as the reply.
+    pub fn spawn<F, Fut>(
+        reader: Box<dyn TransportRecvStream>,
+        writer: Box<dyn TransportSendStream>,
+        handler: F,
+    ) -> Arc<Self>
+    where
+        F: Fn(TunnelCommandPacket) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<TunnelCommandPacket>> + Send + 'static,
+    {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<TunnelCommandPacket>();
+        let channel = Arc::new(Self {
+            sender,
+            pending: Mutex::new(VecDeque::new()),
+            closed: AtomicBool::new(false),
+            closed_notify: Notify::new(),
+        });
+
+        let writer_channel = channel.clone();
+        tokio::spawn(async move {
+            let mut framed = FramedWrite::new(writer, TunnelCodec::default());
+            while let Some(packet) = receiver.recv().await {
+                let command = packet.command;
+                if let Err(e) = framed.send(packet).await {
+                    eprintln!("[Control] Failed to send {:?}: {}", command, e);
+                    break;
+                }
+            }
+            let _ = SinkExt::<TunnelCommandPacket>::close(&mut framed).await;
+            writer_channel.close();
+        });
+
+        let reader_channel = channel.clone();
+        tokio::spawn(async move {
+            let mut framed = FramedRead::new(reader, TunnelCodec::default());
+            while let Some(result) = framed.next().await {
+                let packet = match result {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        eprintln!("[Control] Failed to read command packet: {}", e);
+                        if e.is_recoverable() {
+                            let _ = reader_channel.send(TunnelCommandPacket::error(&e));
+                        }
+                        break;
+                    }
+                };
+                if packet.command.is_response() {
+                    let waiter = reader_channel.pending.lock().unwrap().pop_front();
+                    match waiter {
+                        Some(waiter) => {
+                            let _ = waiter.send(packet);
+                        }
+                        None => eprintln!("[Control] Unsolicited {:?}", packet.command),
+                    }
+                    continue;
+                }
+                if let Some(reply) = handler(packet).await {
+                    let _ = reader_channel.send(reply);
+                }
+            }
+            reader_channel.close();
+        });
+
+        channel
+    }
+
+    /// Sends a request and waits for its response.
+    pub async fn request(
+        &self,
+        packet: TunnelCommandPacket,
+    ) -> anyhow::Result<TunnelCommandPacket> {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            pending.push_back(tx);
+            self.send(packet)?;
+        }
+        rx.await
+            .map_err(|_| anyhow::anyhow!("Control channel closed"))?
+            .into_result()
+    }
+
+    /// Sends a one-way command.
+    pub fn send(&self, packet: TunnelCommandPacket) -> anyhow::Result<()> {
+        if self.is_closed() {
+            return Err(anyhow::anyhow!("Control channel closed"));
+        }
+        self.sender
+            .send(packet)
+            .map_err(|_| anyhow::anyhow!("Control channel closed"))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Resolves once the control stream is gone in either direction.
+    pub async fn closed(&self) {
+        let notified = self.closed_notify.notified();
+        if self.is_closed() {
+            return;
+        }
+        notified.await;
+    }
+
+    fn close(&self) {
+        if !self.closed.swap(true, Ordering::AcqRel) {
+            self.pending.lock().unwrap().clear();
+            self.closed_notify.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::base::TransportStream;
+    use crate::tunnel::packet::TunnelCommand;
+    use crate::tunnel::payload::{Ping, Pong};
+
+    fn pong_handler(
+        packet: TunnelCommandPacket,
+    ) -> impl Future<Output = Option<TunnelCommandPacket>> {
+        let reply = packet
+            .payload::<Ping>()
+            .ok()
+            .map(|ping| TunnelCommandPacket::from_payload(&Pong::from(&ping)));
+        async move { reply }
+    }
+
+    #[tokio::test]
+    async fn requests_are_answered_in_both_directions() {
+        let (a, b) = tokio::io::duplex(4096);
+        let (reader, writer) = Box::new(a).into_split();
+        let edge = ControlChannel::spawn(reader, writer, pong_handler);
+        let (reader, writer) = Box::new(b).into_split();
+        let supernode = ControlChannel::spawn(reader, writer, pong_handler);
+
+        for (from, seq) in [(&edge, 1), (&supernode, 2), (&edge, 3)] {
+            let ping = Ping {
+                seq,
+                ..Default::default()
+            };
+            let reply = from
+                .request(TunnelCommandPacket::from_payload(&ping))
+                .await
+                .unwrap();
+            assert_eq!(reply.command, TunnelCommand::Pong);
+            assert_eq!(reply.payload::<Pong>().unwrap().seq, seq);
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_resolves_when_peer_goes_away() {
+        let (a, b) = tokio::io::duplex(4096);
+        let (reader, writer) = Box::new(a).into_split();
+        let channel = ControlChannel::spawn(reader, writer, pong_handler);
+        drop(b);
+        channel.closed().await;
+        assert!(channel.is_closed());
+        let ping = TunnelCommandPacket::from_payload(&Ping::default());
+        assert!(channel.request(ping).await.is_err());
+    }
+}
@@ -1,14 +1,32 @@
-use crate::transport::base::{ClientConfig, TransformClient};
+use crate::transport::base::{ClientConfig, TransformClient, TransportConnection};
+use crate::transport::cert::ClientTlsConfig;
 use crate::transport::quic::QuinnClientEndpoint;
-use crate::tunnel::common::AUTH_TOKEN_KEY;
+use crate::tunnel::common::{
+    CLOSE_DRAINED, CLOSE_KICKED, CLOSE_REPLACED, CLOSE_REVOKED, COMMAND_TIMEOUT, unix_millis,
+};
+use crate::tunnel::control::ControlChannel;
+use crate::tunnel::egress::ForwardOverrides;
+use crate::tunnel::error::ProtocolError;
 use crate::tunnel::inbound::{InboundConfig, bind_tcp_inbound};
-use crate::tunnel::outbound::forward_to_tcp;
+use crate::tunnel::outbound::{ForwardTarget, forward_to_tcp};
 use crate::tunnel::packet::{TunnelCommand, TunnelCommandPacket, TunnelMeta};
+use crate::tunnel::payload::{
+    AuthChallenge, AuthRequest, AuthResult, CommandPayload, Drain, Kick, Message, Ping, Pong,
+    Reconfigure, SessionMetaResult, SessionMetaUpdate,
+};
 use crate::tunnel::session::DEFAULT_CLIENT_ID;
 use crate::tunnel::session::{TRANSPORT_SESSION_MAP, TransportSession, get_default_session};
-use serde_json::Value;
+use crate::tunnel::supernode::response_error;
+use crate::tunnel::token::{AUTH_EXPORTER_LABEL, auth_proof, token_id};
+use crate::tunnel::version::{CAP_CONTROL_STREAM, CAP_SESSION_META, ProtocolInfo};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
 use std::time::Duration;
 use tokio::io::AsyncWriteExt;
+use tokio::sync::RwLock;
+use tokio::time::Instant;
 
 pub async fn start_client(
     server_addr: String,
@@ -23,6 +41,7 @@ pub async fn start_client(
         }
         result = bind_tcp_inbound(InboundConfig {
             inbound_addr: "127.0.0.1:0".to_string(),
+            tls_termination: None,
         }) => {
             if let Err(e) = result {
                 eprintln!("Inbound error: {:?}", e);
@@ -37,16 +56,29 @@ async fn start_transport(
     token: String,
     forward_to: String,
 ) -> anyhow::Result<()> {
-    let config = ClientConfig {
-        addr: server_addr.clone(),
+    *EDGE_STATE.write().await = EdgeState {
+        server_addr,
+        forward_to,
+        heartbeat_interval: SLEEP_TIME,
+        paused_until: None,
     };
     println!("Connecting to server...");
     let mut is_connected = false;
-    const SLEEP_TIME: Duration = Duration::from_secs(10);
-    let meta = TunnelMeta::from([(AUTH_TOKEN_KEY.to_string(), Value::String(token.clone()))]);
+    let mut ping_seq: u64 = 0;
     loop {
         if !is_connected {
-            let config = config.clone();
+            let paused_until = EDGE_STATE.write().await.paused_until.take();
+            if let Some(until) = paused_until {
+                println!(
+                    "Kicked by supernode, reconnecting in {}s",
+                    until.saturating_duration_since(Instant::now()).as_secs()
+                );
+                tokio::time::sleep_until(until).await;
+            }
+            let config = ClientConfig {
+                addr: EDGE_STATE.read().await.server_addr.clone(),
+                tls: CLIENT_TLS.read().unwrap().clone(),
+            };
             let client = match QuinnClientEndpoint::connect(config).await {
                 Ok(client) => client,
                 Err(e) => {
@@ -58,66 +90,83 @@ async fn start_transport(
                     continue;
                 }
             };
-            TRANSPORT_SESSION_MAP.insert(
-                DEFAULT_CLIENT_ID.to_string(),
-                TransportSession {
-                    conn: client.get_conn(),
-                    meta: std::collections::HashMap::new(),
-                    ping_at: tokio::time::Instant::now(),
-                },
-            );
             println!("Connected successfully!");
 
-            match send_command(TunnelCommand::Auth, &meta).await {
-                Ok(response) => {
-                    if response.meta.get("result").unwrap().as_bool().unwrap() {
-                        println!("Auth successful");
-                    } else {
-                        eprintln!("Auth failed: invalid response");
-                        TRANSPORT_SESSION_MAP.remove(DEFAULT_CLIENT_ID);
-                        tokio::time::sleep(SLEEP_TIME).await;
-                        continue;
-                    }
-                }
+            let conn = client.get_conn();
+            let (protocol, control) = match authenticate(&conn, &token).await {
+                Ok(auth) => auth,
                 Err(e) => {
                     eprintln!("Auth failed: {}", e);
-                    TRANSPORT_SESSION_MAP.remove(DEFAULT_CLIENT_ID);
+                    conn.close(0, "auth failed");
                     tokio::time::sleep(SLEEP_TIME).await;
                     continue;
                 }
+            };
+            println!(
+                "Auth successful, protocol v{} with capabilities {:?}",
+                protocol.version, protocol.capabilities
+            );
+            TRANSPORT_SESSION_MAP.insert(
+                DEFAULT_CLIENT_ID.to_string(),
+                TransportSession {
+                    conn,
+                    client_id: DEFAULT_CLIENT_ID.to_string(),
+                    meta: std::collections::HashMap::new(),
+                    ping_at: tokio::time::Instant::now(),
+                    protocol,
+                    token_id: None,
+                    control,
+                    tunnels: Vec::new(),
+                    limits: Default::default(),
+                    egress: None,
+                    active_streams: Default::default(),
+                },
+            );
+            if let Err(e) = push_session_meta().await {
+                eprintln!("Failed to set session meta: {}", e);
             }
             {
-                let forward_to = forward_to.clone();
                 let client_for_accept = client.clone();
                 tokio::spawn(async move {
                     println!("[QUIC Client] Starting accept loop to receive server streams...");
                     if let Err(e) = client_for_accept
-                        .accept(move |stream| {
-                            let forward_to = forward_to.clone();
-                            async move {
-                                let (mut stream_reader, stream_writer) = tokio::io::split(stream);
-                                let packet =
-                                    TunnelCommandPacket::read_command1(&mut stream_reader).await?;
-                                println!("[QUIC Client] Received command: {:?}", packet);
-                                match packet.command {
-                                    TunnelCommand::Forward => {
-                                        forward_to_tcp(
-                                            stream_reader,
-                                            stream_writer,
-                                            packet,
-                                            Some(forward_to),
-                                        )
-                                        .await?;
-                                    }
-                                    _ => {
+                        .accept(move |stream| async move {
+                            let (mut stream_reader, stream_writer) = stream.into_split();
+                            let packet =
+                                match TunnelCommandPacket::read_from(&mut stream_reader).await {
+                                    Ok(packet) => packet,
+                                    Err(err) => {
                                         eprintln!(
-                                            "[QUIC Client] Unsupported command: {:?}",
-                                            packet.command
+                                            "[QUIC Client] Failed to read command packet: {:?}",
+                                            err
                                         );
+                                        if err.is_recoverable() {
+                                            let _ = response_error(stream_writer, &err).await;
+                                        }
+                                        return Err(err.into());
                                     }
+                                };
+                            println!("[QUIC Client] Received command: {:?}", packet);
+                            match packet.command {
+                                TunnelCommand::Forward => {
+                                    let target = ForwardTarget::Local {
+                                        forward_to: EDGE_STATE.read().await.forward_to.clone(),
+                                        overrides: FORWARD_OVERRIDES.read().unwrap().clone(),
+                                    };
+                                    forward_to_tcp(stream_reader, stream_writer, packet, target)
+                                        .await?;
+                                }
+                                _ => {
+                                    eprintln!(
+                                        "[QUIC Client] Unsupported command: {:?}",
+                                        packet.command
+                                    );
+                                    let err =
+                                        ProtocolError::UnexpectedCommand(packet.command as u8);
+                                    response_error(stream_writer, &err).await?;
                                 }
-                                Ok(())
                             }
+                            Ok(())
                         })
                         .await
                     {
@@ -127,15 +176,25 @@ async fn start_transport(
             }
         }
 
-        match send_command(TunnelCommand::Ping, &meta).await {
+        ping_seq += 1;
+        let ping = Ping {
+            seq: ping_seq,
+            sent_at: unix_millis(),
+            token: LEGACY_AUTH.load(Ordering::Relaxed).then(|| token.clone()),
+            ..Default::default()
+        };
+        match send_command(&ping).await {
             Ok(response) => {
-                if response.command as u8 == TunnelCommand::Pong as u8 {
-                    println!("Ping successful");
+                if let Ok(pong) = response.payload::<Pong>() {
+                    println!(
+                        "Ping successful, rtt {}ms",
+                        unix_millis().saturating_sub(pong.sent_at)
+                    );
                     is_connected = true;
                 } else {
                     eprintln!("Ping failed: invalid response");
                     is_connected = false;
-                    TRANSPORT_SESSION_MAP.remove(DEFAULT_CLIENT_ID);
+                    drop_session()?;
                 }
             }
             Err(e) => {
@@ -144,53 +203,313 @@ async fn start_transport(
                     e
                 );
                 is_connected = false;
-                TRANSPORT_SESSION_MAP.remove(DEFAULT_CLIENT_ID);
+                drop_session()?;
                 // 连接断开时立即重连，不等待
                 continue;
             }
         }
-        tokio::time::sleep(SLEEP_TIME).await;
-        continue;
+        let heartbeat_interval = EDGE_STATE.read().await.heartbeat_interval;
+        let control = get_default_session().and_then(|session| session.control);
+        match control {
+            Some(control) => {
+                tokio::select! {
+                    _ = tokio::time::sleep(heartbeat_interval) => {}
+                    _ = control.closed() => {
+                        eprintln!("Control stream closed, will reconnect");
+                        is_connected = false;
+                        drop_session()?;
+                    }
+                }
+            }
+            None => tokio::time::sleep(heartbeat_interval).await,
+        }
+    }
+}
+
+static CLIENT_TLS: LazyLock<std::sync::RwLock<ClientTlsConfig>> = LazyLock::new(Default::default);
+
+/// Sets how the supernode's certificate is verified on the next connect.
+pub fn set_client_tls(config: ClientTlsConfig) {
+    *CLIENT_TLS.write().unwrap() = config;
+}
+
+static FORWARD_OVERRIDES: LazyLock<std::sync::RwLock<ForwardOverrides>> =
+    LazyLock::new(Default::default);
+
+/// Sets which targets public clients may ask for with `X-Tunnel-Forward-To`.
+pub fn set_forward_overrides(overrides: ForwardOverrides) {
+    *FORWARD_OVERRIDES.write().unwrap() = overrides;
+}
+
+static LEGACY_AUTH: AtomicBool = AtomicBool::new(false);
+
+/// Sends the token itself in `Auth`, for supernodes that predate `AuthChallenge`
+/// or have no token registry. Anyone able to intercept the connection learns it.
+pub fn set_legacy_auth(enabled: bool) {
+    LEGACY_AUTH.store(enabled, Ordering::Relaxed);
+}
+
+/// Sends `Auth` on a fresh stream. When the supernode supports it, that stream
+/// is kept as the control stream for the rest of the connection.
+async fn authenticate(
+    conn: &Arc<dyn TransportConnection + Send + Sync + 'static>,
+    token: &str,
+) -> anyhow::Result<(ProtocolInfo, Option<Arc<ControlChannel>>)> {
+    let legacy = LEGACY_AUTH.load(Ordering::Relaxed);
+    let mut request = AuthRequest::new(token);
+    if !legacy && !token.is_empty() {
+        request.token = String::new();
+        request.token_id = Some(token_id(token));
+    }
+    let (result, stream, challenged) = tokio::time::timeout(COMMAND_TIMEOUT, async {
+        let mut stream = conn.open_stream().await?;
+        TunnelCommandPacket::from_payload(&request)
+            .write_to(&mut stream)
+            .await?;
+        stream.flush().await?;
+        let mut reply = TunnelCommandPacket::read_from(&mut stream)
+            .await?
+            .into_result()?;
+        let challenged = reply.command == TunnelCommand::AuthChallenge;
+        if challenged {
+            let proof = AuthRequest {
+                token_id: request.token_id.clone(),
+                proof: Some(prove(conn, token, &reply.payload::<AuthChallenge>()?)?),
+                ..Default::default()
+            };
+            TunnelCommandPacket::from_payload(&proof)
+                .with_encoding(reply.encoding)
+                .write_to(&mut stream)
+                .await?;
+            stream.flush().await?;
+            reply = TunnelCommandPacket::read_from(&mut stream)
+                .await?
+                .into_result()?;
+        }
+        let result = reply.payload::<AuthResult>()?;
+        anyhow::Ok((result, stream, challenged))
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("Command timeout"))??;
+    if !result.ok {
+        return Err(anyhow::anyhow!(
+            "{}",
+            result.reason.unwrap_or("invalid response".to_string())
+        ));
+    }
+    if request.token_id.is_some() && !challenged {
+        // A supernode that accepts without a challenge never checked the token.
+        return Err(anyhow::anyhow!(
+            "Supernode does not support challenge auth, use legacy auth to connect to it"
+        ));
+    }
+    let protocol = result.protocol()?;
+    let control = if protocol.supports(CAP_CONTROL_STREAM) {
+        let (reader, writer) = stream.into_split();
+        Some(ControlChannel::spawn(reader, writer, handle_server_command))
+    } else {
+        let mut stream = stream;
+        let _ = stream.shutdown().await;
+        None
+    };
+    Ok((protocol, control))
+}
+
+fn prove(
+    conn: &Arc<dyn TransportConnection + Send + Sync + 'static>,
+    token: &str,
+    challenge: &AuthChallenge,
+) -> anyhow::Result<String> {
+    let nonce = URL_SAFE_NO_PAD
+        .decode(&challenge.nonce)
+        .map_err(|_| anyhow::anyhow!("Invalid auth challenge"))?;
+    let mut exporter = [0u8; 32];
+    conn.export_keying_material(&mut exporter, AUTH_EXPORTER_LABEL, &[])?;
+    Ok(URL_SAFE_NO_PAD.encode(auth_proof(token, &nonce, &exporter)))
+}
+
+/// Answers commands the supernode sends on the control stream.
+async fn handle_server_command(packet: TunnelCommandPacket) -> Option<TunnelCommandPacket> {
+    println!(
+        "[QUIC Client] Received control command: {:?}",
+        packet.command
+    );
+    let reply = match packet.command {
+        TunnelCommand::Ping => packet.payload::<Ping>().map(|ping| {
+            TunnelCommandPacket::from_payload(&Pong::from(&ping)).with_encoding(packet.encoding)
+        }),
+        TunnelCommand::Kick
+        | TunnelCommand::Drain
+        | TunnelCommand::Reconfigure
+        | TunnelCommand::Message => {
+            // One-way commands get no reply, not even on failure.
+            if let Err(err) = apply_server_command(&packet).await {
+                eprintln!("[QUIC Client] Invalid {:?}: {}", packet.command, err);
+            }
+            return None;
+        }
+        _ => Err(ProtocolError::UnexpectedCommand(packet.command as u8)),
+    };
+    Some(reply.unwrap_or_else(|err| TunnelCommandPacket::error(&err)))
+}
+
+async fn apply_server_command(packet: &TunnelCommandPacket) -> Result<(), ProtocolError> {
+    match packet.command {
+        TunnelCommand::Kick => {
+            let kick = packet.payload::<Kick>()?;
+            let reason = kick.reason.unwrap_or_default();
+            eprintln!(
+                "[QUIC Client] Kicked by supernode: {} (retry after {}s)",
+                reason, kick.retry_after
+            );
+            EDGE_STATE.write().await.paused_until =
+                Some(Instant::now() + Duration::from_secs(kick.retry_after));
+            close_connection(CLOSE_KICKED, &reason);
+        }
+        TunnelCommand::Drain => {
+            let drain = packet.payload::<Drain>()?;
+            let mut state = EDGE_STATE.write().await;
+            if let Some(server_addr) = drain.server_addr {
+                state.server_addr = server_addr;
+            }
+            println!(
+                "[QUIC Client] Drained by supernode: {}, moving to {}",
+                drain.reason.unwrap_or_default(),
+                state.server_addr
+            );
+            close_connection(CLOSE_DRAINED, "drained");
+        }
+        TunnelCommand::Reconfigure => {
+            let reconfigure = packet.payload::<Reconfigure>()?;
+            let mut state = EDGE_STATE.write().await;
+            if let Some(forward_to) = reconfigure.forward_to {
+                state.forward_to = forward_to;
+            }
+            if let Some(secs) = reconfigure.heartbeat_interval {
+                state.heartbeat_interval = Duration::from_secs(secs.max(1));
+            }
+            println!(
+                "[QUIC Client] Reconfigured: forward_to={} heartbeat={}s",
+                state.forward_to,
+                state.heartbeat_interval.as_secs()
+            );
+        }
+        TunnelCommand::Message => {
+            let message = packet.payload::<Message>()?;
+            println!("[QUIC Client] Message from supernode: {}", message.text);
+        }
+        _ => return Err(ProtocolError::UnexpectedCommand(packet.command as u8)),
+    }
+    Ok(())
+}
+
+/// Forgets the current connection. Fails when the supernode closed it because
+/// this edge was revoked or replaced, so the caller stops instead of reconnecting.
+fn drop_session() -> anyhow::Result<()> {
+    let Some((_, session)) = TRANSPORT_SESSION_MAP.remove(DEFAULT_CLIENT_ID) else {
+        return Ok(());
+    };
+    match session.conn.peer_close() {
+        Some((CLOSE_REVOKED, reason)) => Err(anyhow::anyhow!("Revoked by supernode: {}", reason)),
+        Some((CLOSE_REPLACED, reason)) => Err(anyhow::anyhow!("Session {}", reason)),
+        _ => Ok(()),
     }
 }
 
-async fn send_command(
-    command: TunnelCommand,
-    meta: &TunnelMeta,
+fn close_connection(code: u32, reason: &str) {
+    if let Some(session) = get_default_session() {
+        session.conn.close(code, reason);
+    }
+}
+
+const SLEEP_TIME: Duration = Duration::from_secs(10);
+
+/// Settings of the running edge that the supernode may change at runtime.
+#[derive(Default)]
+struct EdgeState {
+    server_addr: String,
+    forward_to: String,
+    heartbeat_interval: Duration,
+    /// Set by `Kick`; no reconnect is attempted before this instant.
+    paused_until: Option<Instant>,
+}
+
+static EDGE_STATE: LazyLock<RwLock<EdgeState>> =
+    LazyLock::new(|| RwLock::new(EdgeState::default()));
+
+static LOCAL_SESSION_META: LazyLock<RwLock<SessionMetaUpdate>> =
+    LazyLock::new(|| RwLock::new(SessionMetaUpdate::default()));
+
+/// Updates this edge's session metadata on the supernode and returns the merged
+/// result, or `None` while disconnected. The update is also remembered locally
+/// and sent again after every reconnect.
+pub async fn set_session_meta(update: SessionMetaUpdate) -> anyhow::Result<Option<TunnelMeta>> {
+    LOCAL_SESSION_META.write().await.merge(update.clone());
+    if get_default_session().is_none() {
+        return Ok(None);
+    }
+    send_session_meta(&update).await.map(Some)
+}
+
+async fn push_session_meta() -> anyhow::Result<()> {
+    let update = LOCAL_SESSION_META.read().await.clone();
+    if !update.is_empty() {
+        send_session_meta(&update).await?;
+    }
+    Ok(())
+}
+
+async fn send_session_meta(update: &SessionMetaUpdate) -> anyhow::Result<TunnelMeta> {
+    let session = get_default_session().ok_or(anyhow::anyhow!("Not connected"))?;
+    if !session.protocol.supports(CAP_SESSION_META) {
+        return Err(anyhow::anyhow!(
+            "Supernode does not support updating session meta"
+        ));
+    }
+    let result = send_command(update).await?.payload::<SessionMetaResult>()?;
+    if !result.ok {
+        return Err(anyhow::anyhow!(
+            "Session meta rejected: {}",
+            result.reason.unwrap_or_default()
+        ));
+    }
+    Ok(result.meta)
+}
+
+async fn send_command<P: CommandPayload>(
+    payload: &P,
 ) -> Result<TunnelCommandPacket, anyhow::Error> {
     let session = get_default_session();
     if let Some(session) = session {
-        tokio::time::timeout(Duration::from_secs(10), async {
+        let command_packet = session.protocol.packet(payload)?;
+        if let Some(control) = &session.control {
+            return tokio::time::timeout(COMMAND_TIMEOUT, control.request(command_packet))
+                .await
+                .map_err(|_| anyhow::anyhow!("Command timeout"))?;
+        }
+        tokio::time::timeout(COMMAND_TIMEOUT, async {
             let stream = session
                 .conn
                 .open_stream()
                 .await
                 .map_err(|e| anyhow::anyhow!("Connection closed: {}", e))?;
             let (mut recv_stream, mut send_stream) = tokio::io::split(stream);
-            let command_packet = TunnelCommandPacket::new(command, meta);
-            send_stream
-                .write_all(&command_packet.to_bytes())
+            command_packet
+                .write_to(&mut send_stream)
                 .await
                 .map_err(|e| anyhow::anyhow!("Write error: {}", e))?;
             send_stream
                 .flush()
                 .await
                 .map_err(|e| anyhow::anyhow!("Flush error: {}", e))?;
-            let response_packet = TunnelCommandPacket::read_command1(&mut recv_stream)
+            let response_packet = TunnelCommandPacket::read_from(&mut recv_stream)
                 .await
                 .map_err(|e| anyhow::anyhow!("Read error: {}", e))?;
             let _ = send_stream.shutdown().await;
-            Ok(response_packet)
+            response_packet.into_result()
         })
         .await
-        .map_err(|e| {
-            let err_msg = e.to_string();
-            if err_msg.contains("deadline has elapsed") || err_msg.contains("timeout") {
-                anyhow::anyhow!("Command timeout")
-            } else {
-                anyhow::anyhow!("Command error: {}", e)
-            }
-        })?
+        .map_err(|_| anyhow::anyhow!("Command timeout"))?
     } else {
         Err(anyhow::anyhow!("Default connection not found"))
     }
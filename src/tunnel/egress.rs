@@ -0,0 +1,360 @@
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{LazyLock, RwLock};
+
+/// Addresses and ports matched by an egress rule:
+/// `10.0.0.0/8`, `10.0.0.0/8:5432`, `*:443`, `192.168.1.10:8000-8100`, `[fd00::/8]:22`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct EgressRule {
+    /// `None` matches every address.
+    pub net: Option<IpNet>,
+    /// Inclusive port range; `None` matches every port.
+    pub ports: Option<(u16, u16)>,
+}
+
+impl EgressRule {
+    pub fn matches(&self, addr: &SocketAddr) -> bool {
+        self.net.is_none_or(|net| net.contains(&addr.ip()))
+            && self
+                .ports
+                .is_none_or(|(from, to)| (from..=to).contains(&addr.port()))
+    }
+}
+
+impl FromStr for EgressRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (net, ports) = if let Some(rest) = s.strip_prefix('[') {
+            let (net, rest) = rest
+                .split_once(']')
+                .ok_or(anyhow::anyhow!("Invalid egress rule {}: missing ]", s))?;
+            (net, rest.strip_prefix(':'))
+        } else if s.matches(':').count() == 1 {
+            let (net, ports) = s.split_once(':').unwrap();
+            (net, Some(ports))
+        } else {
+            (s, None)
+        };
+        let net = match net {
+            "*" => None,
+            net if net.contains('/') => Some(net.parse::<IpNet>()?),
+            ip => Some(IpNet::from(ip.parse::<IpAddr>()?)),
+        };
+        let ports = match ports {
+            None => None,
+            Some(ports) => Some(match ports.split_once('-') {
+                Some((from, to)) => (from.parse()?, to.parse()?),
+                None => (ports.parse()?, ports.parse()?),
+            }),
+        };
+        Ok(Self { net, ports })
+    }
+}
+
+impl TryFrom<String> for EgressRule {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<EgressRule> for String {
+    fn from(rule: EgressRule) -> Self {
+        rule.to_string()
+    }
+}
+
+impl fmt::Display for EgressRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let net = self.net.map_or("*".to_string(), |net| net.to_string());
+        match (self.net, self.ports) {
+            (_, None) => write!(f, "{}", net),
+            (Some(IpNet::V6(_)), Some((from, to))) if from == to => write!(f, "[{}]:{}", net, from),
+            (Some(IpNet::V6(_)), Some((from, to))) => write!(f, "[{}]:{}-{}", net, from, to),
+            (_, Some((from, to))) if from == to => write!(f, "{}:{}", net, from),
+            (_, Some((from, to))) => write!(f, "{}:{}-{}", net, from, to),
+        }
+    }
+}
+
+/// Where the supernode may connect on behalf of an edge's `Forward`. A target is
+/// refused when a `deny` rule matches, allowed when an `allow` rule matches, and
+/// otherwise refused if it is a private address or `allow` is not empty.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EgressPolicy {
+    #[serde(default)]
+    pub allow: Vec<EgressRule>,
+    #[serde(default)]
+    pub deny: Vec<EgressRule>,
+    /// Refuses loopback, private, link-local and other non-public addresses.
+    #[serde(default = "default_block_private")]
+    pub block_private: bool,
+}
+
+fn default_block_private() -> bool {
+    true
+}
+
+impl Default for EgressPolicy {
+    fn default() -> Self {
+        Self {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            block_private: true,
+        }
+    }
+}
+
+impl EgressPolicy {
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        if self.deny.iter().any(|rule| rule.matches(addr)) {
+            return false;
+        }
+        if self.allow.iter().any(|rule| rule.matches(addr)) {
+            return true;
+        }
+        !(self.block_private && is_private(addr.ip())) && self.allow.is_empty()
+    }
+}
+
+/// Addresses that are not reachable on the public internet, including IPv6
+/// addresses that only wrap such an IPv4 address.
+pub fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                // Carrier-grade NAT, 100.64.0.0/10.
+                || (a == 100 && b & 0xc0 == 64)
+                || a == 0
+                // Benchmarking, 198.18.0.0/15.
+                || (a == 198 && b & 0xfe == 18)
+                // Reserved, 240.0.0.0/4.
+                || a >= 240
+                // Documentation: TEST-NET-1, -2 and -3.
+                || matches!((a, b, c), (192, 0, 2) | (198, 51, 100) | (203, 0, 113))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            // IPv4-mapped and the deprecated IPv4-compatible `::a.b.c.d`.
+            if let Some(v4) = ip.to_ipv4() {
+                return is_private(IpAddr::V4(v4));
+            }
+            // NAT64, 64:ff9b::/96, reaches the IPv4 address in its last 32 bits.
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                return is_private(IpAddr::V4(embedded_ipv4(segments[6], segments[7])));
+            }
+            // 6to4, 2002::/16, reaches the IPv4 address in bits 16 to 48.
+            if segments[0] == 0x2002 {
+                return is_private(IpAddr::V4(embedded_ipv4(segments[1], segments[2])));
+            }
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                // Local-use NAT64, 64:ff9b:1::/48, translates to whatever the operator chose.
+                || segments[..3] == [0x64, 0xff9b, 1]
+                // Deprecated site-local, fec0::/10.
+                || segments[0] & 0xffc0 == 0xfec0
+        }
+    }
+}
+
+fn embedded_ipv4(high: u16, low: u16) -> Ipv4Addr {
+    let [a, b] = high.to_be_bytes();
+    let [c, d] = low.to_be_bytes();
+    Ipv4Addr::new(a, b, c, d)
+}
+
+/// Targets a public client may pick with `X-Tunnel-Forward-To` instead of the
+/// edge's own `forward_to`. Nothing is allowed by default.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ForwardOverrides {
+    /// `host:port` patterns, where `*` in the host matches any characters and a
+    /// `*` port matches any port.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Names a client may ask for instead of an address, mapped to `host:port`.
+    #[serde(default)]
+    pub services: HashMap<String, String>,
+}
+
+impl ForwardOverrides {
+    /// Where to connect for a client asking for `requested`, if that is allowed.
+    pub fn resolve(&self, requested: &str) -> Option<String> {
+        if let Some(target) = self.services.get(requested) {
+            return Some(target.clone());
+        }
+        let (host, port) = requested.rsplit_once(':')?;
+        port.parse::<u16>().ok()?;
+        self.allow
+            .iter()
+            .filter_map(|pattern| pattern.rsplit_once(':'))
+            .any(|(host_pattern, port_pattern)| {
+                (port_pattern == "*" || port_pattern == port) && wildcard_match(host_pattern, host)
+            })
+            .then(|| requested.to_string())
+    }
+}
+
+/// Matches `text` against `pattern`, where `*` stands for any run of characters.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern.eq_ignore_ascii_case(text),
+        Some((prefix, rest)) => {
+            text.len() >= prefix.len()
+                && text.is_char_boundary(prefix.len())
+                && text[..prefix.len()].eq_ignore_ascii_case(prefix)
+                && (prefix.len()..=text.len())
+                    .filter(|i| text.is_char_boundary(*i))
+                    .any(|i| wildcard_match(rest, &text[i..]))
+        }
+    }
+}
+
+static EGRESS_POLICY: LazyLock<RwLock<EgressPolicy>> =
+    LazyLock::new(|| RwLock::new(EgressPolicy::default()));
+
+/// Sets the policy for edges whose token does not carry its own.
+pub fn set_egress_policy(policy: EgressPolicy) {
+    *EGRESS_POLICY.write().unwrap() = policy;
+}
+
+pub fn egress_policy() -> EgressPolicy {
+    EGRESS_POLICY.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn rules_parse_and_print() {
+        for rule in [
+            "10.0.0.0/8",
+            "10.0.0.0/8:5432",
+            "*:443",
+            "192.168.1.10/32:8000-8100",
+            "[fd00::/8]:22",
+            "fd00::/8",
+        ] {
+            assert_eq!(rule.parse::<EgressRule>().unwrap().to_string(), rule);
+        }
+        let rule: EgressRule = "192.168.1.10:80".parse().unwrap();
+        assert!(rule.matches(&addr("192.168.1.10:80")));
+        assert!(!rule.matches(&addr("192.168.1.11:80")));
+        assert!(!rule.matches(&addr("192.168.1.10:81")));
+        assert!("10.0.0.0/33".parse::<EgressRule>().is_err());
+    }
+
+    #[test]
+    fn overrides_allow_only_listed_targets() {
+        let overrides = ForwardOverrides {
+            allow: vec!["127.0.0.1:*".to_string(), "*.lan:8080".to_string()],
+            services: HashMap::from([("db".to_string(), "10.0.0.5:5432".to_string())]),
+        };
+        assert_eq!(overrides.resolve("db").as_deref(), Some("10.0.0.5:5432"));
+        assert_eq!(
+            overrides.resolve("127.0.0.1:3000").as_deref(),
+            Some("127.0.0.1:3000")
+        );
+        assert!(overrides.resolve("nas.lan:8080").is_some());
+        assert!(overrides.resolve("nas.lan:22").is_none());
+        assert!(overrides.resolve("127.0.0.2:3000").is_none());
+        assert!(overrides.resolve("127.0.0.1:x").is_none());
+        assert!(overrides.resolve("10.0.0.5:5432").is_none());
+        assert!(
+            ForwardOverrides::default()
+                .resolve("127.0.0.1:80")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn private_ranges_are_blocked_by_default() {
+        let policy = EgressPolicy::default();
+        assert!(policy.allows(&addr("93.184.216.34:80")));
+        for private in [
+            "127.0.0.1:80",
+            "10.1.2.3:80",
+            "192.168.0.1:80",
+            "169.254.169.254:80",
+            "100.64.0.1:80",
+            "0.0.0.0:80",
+            "[::1]:80",
+            "[fd00::1]:80",
+            "[::ffff:10.0.0.1]:80",
+            "198.18.0.1:80",
+            "198.19.255.255:80",
+            "240.0.0.1:80",
+            "192.0.2.1:80",
+            "198.51.100.1:80",
+            "203.0.113.1:80",
+            "[fec0::1]:80",
+            "[64:ff9b:1::a00:1]:80",
+        ] {
+            assert!(!policy.allows(&addr(private)), "{}", private);
+        }
+        assert!(policy.allows(&addr("198.20.0.1:80")));
+        assert!(policy.allows(&addr("[2606:4700::1111]:80")));
+    }
+
+    #[test]
+    fn ipv6_wrapping_a_private_ipv4_is_blocked() {
+        let policy = EgressPolicy::default();
+        // NAT64 and 6to4 of 127.0.0.1, 10.0.0.1 and 169.254.169.254.
+        for private in [
+            "[64:ff9b::7f00:1]:80",
+            "[64:ff9b::10.0.0.1]:80",
+            "[64:ff9b::a9fe:a9fe]:80",
+            "[2002:7f00:1::1]:80",
+            "[2002:a00:1::]:80",
+            "[2002:a9fe:a9fe:1::1]:80",
+            "[::10.0.0.1]:80",
+        ] {
+            assert!(!policy.allows(&addr(private)), "{}", private);
+        }
+        // The same prefixes in front of a public address are fine.
+        assert!(policy.allows(&addr("[64:ff9b::5db8:d822]:80")));
+        assert!(policy.allows(&addr("[2002:5db8:d822::1]:80")));
+    }
+
+    #[test]
+    fn deny_beats_allow_and_allow_beats_private() {
+        let policy = EgressPolicy {
+            allow: vec!["10.0.0.0/8:5432".parse().unwrap()],
+            deny: vec!["10.0.0.66".parse().unwrap()],
+            block_private: true,
+        };
+        assert!(policy.allows(&addr("10.0.0.5:5432")));
+        assert!(!policy.allows(&addr("10.0.0.66:5432")));
+        assert!(!policy.allows(&addr("10.0.0.5:22")));
+        // A non-empty allow list refuses everything it does not list.
+        assert!(!policy.allows(&addr("93.184.216.34:80")));
+
+        let policy = EgressPolicy {
+            deny: vec!["*:25".parse().unwrap()],
+            block_private: false,
+            ..Default::default()
+        };
+        assert!(policy.allows(&addr("10.0.0.5:80")));
+        assert!(!policy.allows(&addr("93.184.216.34:25")));
+    }
+}
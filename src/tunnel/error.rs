@@ -0,0 +1,73 @@
+use std::fmt;
+
+/// Everything that can go wrong while decoding or interpreting a command packet.
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(std::io::Error),
+    InvalidCommand(u8),
+    DataTooLarge(usize),
+    InvalidMeta(String),
+    MissingField(&'static str),
+    InvalidField(&'static str),
+    UnexpectedCommand(u8),
+    Unauthenticated,
+}
+
+impl ProtocolError {
+    /// Stable machine-readable code, sent to the peer in an `Error` command.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProtocolError::Io(_) => "io",
+            ProtocolError::InvalidCommand(_) => "invalid_command",
+            ProtocolError::DataTooLarge(_) => "data_too_large",
+            ProtocolError::InvalidMeta(_) => "invalid_meta",
+            ProtocolError::MissingField(_) => "missing_field",
+            ProtocolError::InvalidField(_) => "invalid_field",
+            ProtocolError::UnexpectedCommand(_) => "unexpected_command",
+            ProtocolError::Unauthenticated => "unauthenticated",
+        }
+    }
+
+    /// Whether the peer is still worth replying to. I/O failures mean the stream is gone.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(self, ProtocolError::Io(_))
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Io(e) => write!(f, "I/O error: {}", e),
+            ProtocolError::InvalidCommand(command) => write!(f, "Invalid command type {}", command),
+            ProtocolError::DataTooLarge(length) => write!(f, "Data length is too large {}", length),
+            ProtocolError::InvalidMeta(e) => write!(f, "Invalid meta: {}", e),
+            ProtocolError::MissingField(key) => write!(f, "Missing meta field {}", key),
+            ProtocolError::InvalidField(key) => write!(f, "Invalid meta field {}", key),
+            ProtocolError::UnexpectedCommand(command) => {
+                write!(f, "Unexpected command {}", command)
+            }
+            ProtocolError::Unauthenticated => write!(f, "Connection is not authenticated"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ProtocolError {
+    fn from(e: std::io::Error) -> Self {
+        ProtocolError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ProtocolError {
+    fn from(e: serde_json::Error) -> Self {
+        ProtocolError::InvalidMeta(e.to_string())
+    }
+}
@@ -1,14 +1,26 @@
 use serde_json::{Value, json};
+use std::io::Cursor;
 use std::sync::{Arc, LazyLock};
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpListener;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, Chain, ReadHalf, WriteHalf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::RwLock;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
 
+use crate::transport::base::{TransportRecvStream, TransportSendStream};
+use crate::transport::cert::{CERT_RELOAD_INTERVAL, CertDirResolver};
 use crate::tunnel::{
-    common::FORWARD_TO_KEY,
-    packet::{TunnelCommand, TunnelCommandPacket, TunnelMeta},
-    session::{TRANSPORT_SESSION_MAP, get_default_session, get_session},
-    sniff,
+    acme::{self, AcmeConfig, OnDemandIssuer, spawn_on_demand, spawn_renewal},
+    common::{FORWARD_RESULT_TIMEOUT, new_trace_id},
+    packet::TunnelCommandPacket,
+    payload::{ForwardFailure, ForwardRequest, ForwardResult},
+    relay::{relay, relay_io},
+    session::{
+        SESSION_TIMEOUT, TRANSPORT_SESSION_MAP, get_default_session, get_session, resolve_tunnel,
+    },
+    sniff::{self, SniffResult},
+    version::CAP_FORWARD_RESULT,
 };
 
 pub static TCP_INBOUND_ADDR: LazyLock<Arc<RwLock<String>>> =
@@ -16,15 +28,154 @@ pub static TCP_INBOUND_ADDR: LazyLock<Arc<RwLock<String>>> =
 
 pub struct InboundConfig {
     pub inbound_addr: String,
+    pub tls_termination: Option<TlsTermination>,
+}
+
+/// Tunnels whose public TLS connections the supernode decrypts with a
+/// certificate from `cert_dir`, forwarding plain HTTP to the edge. Connections
+/// for other tunnels, or for names without a certificate, are passed through.
+#[derive(Debug, Clone, Default)]
+pub struct TlsTermination {
+    pub cert_dir: String,
+    /// Tunnel ids, or `*` for every tunnel.
+    pub tunnels: Vec<String>,
+    /// Keeps certificates in `cert_dir` issued by an ACME directory.
+    pub acme: Option<AcmeConfig>,
+}
+
+impl TlsTermination {
+    pub fn applies_to(&self, tunnel_id: &str) -> bool {
+        self.tunnels
+            .iter()
+            .any(|tunnel| tunnel == "*" || tunnel == tunnel_id)
+    }
+}
+
+struct TlsTerminator {
+    termination: TlsTermination,
+    resolver: Arc<CertDirResolver>,
+    on_demand: Option<Arc<OnDemandIssuer>>,
+    acceptor: TlsAcceptor,
+}
+
+impl TlsTerminator {
+    fn new(termination: TlsTermination) -> anyhow::Result<Self> {
+        let resolver = CertDirResolver::load(&termination.cert_dir)?;
+        resolver.watch(CERT_RELOAD_INTERVAL);
+        let mut on_demand = None;
+        if let Some(acme) = &termination.acme {
+            spawn_renewal(acme.clone(), termination.cert_dir.clone(), resolver.clone());
+            if !acme.on_demand_suffixes.is_empty() {
+                on_demand = Some(spawn_on_demand(
+                    acme.clone(),
+                    termination.cert_dir.clone(),
+                    resolver.clone(),
+                ));
+            }
+        }
+        let mut tls_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Self {
+            termination,
+            resolver,
+            on_demand,
+            acceptor: TlsAcceptor::from(Arc::new(tls_config)),
+        })
+    }
+
+    /// The server name of a TLS connection this terminator should decrypt.
+    /// Names of online tunnels without a certificate are passed through while
+    /// one is ordered, if they are under an on-demand suffix.
+    fn server_name(&self, request_info: &SniffResult) -> Option<String> {
+        let (server_name, _) = request_info.host.rsplit_once(':')?;
+        if !request_info.is_https || !self.termination.applies_to(&request_info.tunnel_id) {
+            return None;
+        }
+        if self.resolver.find(server_name).is_some() {
+            return Some(server_name.to_string());
+        }
+        if let Some(on_demand) = &self.on_demand
+            && resolve_tunnel(&request_info.tunnel_id).is_some()
+        {
+            on_demand.request(&server_name.to_ascii_lowercase());
+        }
+        None
+    }
+
+    /// Completes the handshake and reads the start of the decrypted request, so
+    /// it can be sniffed like a plain HTTP connection.
+    async fn accept(
+        &self,
+        stream: TcpStream,
+        server_name: &str,
+        tunnel_id: String,
+    ) -> anyhow::Result<(PublicStream, SniffResult)> {
+        let tls = self.acceptor.accept(stream).await?;
+        let (mut reader, writer) = tokio::io::split(tls);
+        let mut head = vec![0u8; 4096];
+        let n = reader.read(&mut head).await?;
+        head.truncate(n);
+        let sniffed = sniff::sniff_bytes(&head)
+            .ok()
+            .filter(|sniffed| !sniffed.is_https);
+        let request_info = SniffResult {
+            tunnel_id,
+            host: format!("{}:80", server_name),
+            is_https: false,
+            forward_to: sniffed.as_ref().and_then(|s| s.forward_to.clone()),
+            path: sniffed.and_then(|s| s.path),
+        };
+        Ok((
+            PublicStream::Tls(Cursor::new(head).chain(reader), writer),
+            request_info,
+        ))
+    }
+}
+
+/// A public client's connection, decrypted if the supernode terminated its TLS.
+enum PublicStream {
+    Tcp(OwnedReadHalf, OwnedWriteHalf),
+    Tls(
+        Chain<Cursor<Vec<u8>>, ReadHalf<TlsStream<TcpStream>>>,
+        WriteHalf<TlsStream<TcpStream>>,
+    ),
+}
+
+impl PublicStream {
+    fn writer(&mut self) -> &mut (dyn AsyncWrite + Send + Unpin) {
+        match self {
+            PublicStream::Tcp(_, writer) => writer,
+            PublicStream::Tls(_, writer) => writer,
+        }
+    }
+
+    async fn relay(
+        self,
+        stream_reader: Box<dyn TransportRecvStream>,
+        stream_writer: Box<dyn TransportSendStream>,
+    ) -> std::io::Result<()> {
+        match self {
+            PublicStream::Tcp(reader, writer) => {
+                relay(reader, writer, stream_reader, stream_writer).await
+            }
+            PublicStream::Tls(reader, writer) => {
+                relay_io(reader, writer, stream_reader, stream_writer).await
+            }
+        }
+    }
 }
 pub struct TcpInbound {
     pub listener: TcpListener,
 }
 
 pub async fn bind_tcp_inbound(config: InboundConfig) -> Result<Arc<TcpInbound>, anyhow::Error> {
-    let listener = TcpListener::bind(config.inbound_addr.clone())
-        .await
-        .unwrap();
+    let listener = TcpListener::bind(config.inbound_addr.clone()).await?;
+    let terminator = match config.tls_termination {
+        Some(termination) => Some(Arc::new(TlsTerminator::new(termination)?)),
+        None => None,
+    };
     if let Ok(addr) = listener.local_addr() {
         *TCP_INBOUND_ADDR.write().await = addr.to_string();
         println!("tcp inbound addr: {}", TCP_INBOUND_ADDR.read().await);
@@ -33,75 +184,194 @@ pub async fn bind_tcp_inbound(config: InboundConfig) -> Result<Arc<TcpInbound>,
     loop {
         match listener.accept().await {
             Ok((stream, _addr)) => {
+                let terminator = terminator.clone();
                 tokio::spawn(async move {
-                    let (mut tcp_recv, mut tcp_send) = stream.into_split();
-                    let request_info = match sniff::sniff_tcp(&mut tcp_recv).await {
+                    let (mut tcp_recv, tcp_send) = stream.into_split();
+                    let mut request_info = match sniff::sniff_tcp(&mut tcp_recv).await {
                         Ok(info) => info,
                         Err(e) => {
                             eprintln!("sniff_tcp error: {:?}", e);
                             return;
                         }
                     };
-                    println!("request_info: {:?}", request_info);
+                    let server_name = terminator
+                        .as_ref()
+                        .and_then(|terminator| terminator.server_name(&request_info));
+                    let mut client = match (terminator, server_name) {
+                        (Some(terminator), Some(server_name)) => {
+                            let stream = match tcp_recv.reunite(tcp_send) {
+                                Ok(stream) => stream,
+                                Err(e) => {
+                                    eprintln!("reunite for TLS termination failed: {:?}", e);
+                                    return;
+                                }
+                            };
+                            match terminator
+                                .accept(stream, &server_name, request_info.tunnel_id.clone())
+                                .await
+                            {
+                                Ok((client, decrypted)) => {
+                                    request_info = decrypted;
+                                    client
+                                }
+                                Err(e) => {
+                                    eprintln!(
+                                        "TLS termination for {} failed: {:?}",
+                                        server_name, e
+                                    );
+                                    return;
+                                }
+                            }
+                        }
+                        _ => PublicStream::Tcp(tcp_recv, tcp_send),
+                    };
+                    if let Some(key_authorization) =
+                        request_info.path.as_deref().and_then(acme::http01_response)
+                    {
+                        let _ =
+                            http_response(client.writer(), 200, "text/plain", &key_authorization)
+                                .await;
+                        return;
+                    }
                     let tunnel_id = request_info.tunnel_id.clone();
-                    let session = get_default_session().or_else(|| get_session(&tunnel_id));
-                    println!("session: {:?}", session.is_some());
+                    let session_id = resolve_tunnel(&tunnel_id).unwrap_or_default();
+                    let session = get_default_session().or_else(|| get_session(&session_id));
                     if let Some(session) = session {
-                        if session.ping_at.elapsed().as_secs() > 60 {
+                        if session.ping_at.elapsed() > SESSION_TIMEOUT {
                             eprintln!("session timeout, will remove session");
-                            TRANSPORT_SESSION_MAP.remove(&tunnel_id);
+                            TRANSPORT_SESSION_MAP.remove(&session_id);
+                            reply_failure(
+                                client.writer(),
+                                request_info.is_https,
+                                503,
+                                format!("tunnel [{}] not online", tunnel_id),
+                            )
+                            .await;
                             return;
                         }
+                        let Some(_stream_guard) = session.acquire_stream() else {
+                            reply_failure(
+                                client.writer(),
+                                request_info.is_https,
+                                429,
+                                format!("tunnel [{}] has too many open connections", tunnel_id),
+                            )
+                            .await;
+                            return;
+                        };
                         let upstream_stream = match session.conn.open_stream().await {
                             Ok(stream) => stream,
                             Err(e) => {
                                 eprintln!("open_stream error: {:?}", e);
-                                TRANSPORT_SESSION_MAP.remove(&tunnel_id);
+                                TRANSPORT_SESSION_MAP.remove(&session_id);
+                                reply_failure(
+                                    client.writer(),
+                                    request_info.is_https,
+                                    502,
+                                    format!("tunnel [{}] unreachable", tunnel_id),
+                                )
+                                .await;
                                 return;
                             }
                         };
                         let (mut upstream_reader, mut upstream_writer) =
-                            tokio::io::split(upstream_stream);
+                            upstream_stream.into_split();
 
                         println!("Forwarding HTTP request to: {}", request_info.host);
 
-                        let tcp_to_transport = tokio::spawn(async move {
-                            let meta = TunnelMeta::from([(
-                                FORWARD_TO_KEY.to_string(),
-                                Value::String(request_info.host.clone()),
-                            )]);
-                            let command = TunnelCommandPacket::new(TunnelCommand::Forward, &meta);
-                            println!("Sending Forward command: {:?}", command);
-                            if let Err(e) = upstream_writer.write_all(&command.to_bytes()).await {
-                                eprintln!("Failed to send Forward command: {:?}", e);
+                        let want_result = session.protocol.supports(CAP_FORWARD_RESULT);
+                        let request = ForwardRequest {
+                            target: request_info.host.clone(),
+                            override_target: request_info.forward_to.clone(),
+                            protocol: Some(
+                                if request_info.is_https {
+                                    "https"
+                                } else {
+                                    "http"
+                                }
+                                .to_string(),
+                            ),
+                            trace_id: Some(new_trace_id()),
+                            want_result,
+                            ..Default::default()
+                        };
+                        let command = match session.protocol.packet(&request) {
+                            Ok(command) => command,
+                            Err(e) => {
+                                eprintln!("Failed to encode Forward command: {:?}", e);
                                 return;
                             }
-                            if let Err(e) =
-                                tokio::io::copy(&mut tcp_recv, &mut upstream_writer).await
-                            {
-                                eprintln!("copy stream -> upstream error: {:?}", e);
-                            }
-                            upstream_writer.shutdown().await.ok();
-                        });
-                        let transport_to_tcp = tokio::spawn(async move {
-                            if let Err(e) =
-                                tokio::io::copy(&mut upstream_reader, &mut tcp_send).await
-                            {
-                                eprintln!("copy upstream -> stream error: {:?}", e);
-                            }
-                            tcp_send.shutdown().await.ok();
-                        });
-                        let res = tokio::try_join!(tcp_to_transport, transport_to_tcp);
-                        if let Err(e) = res {
-                            eprintln!("copy stream -> upstream error: {:?}", e);
                         };
+                        if let Err(e) = command.write_to(&mut upstream_writer).await {
+                            eprintln!("Failed to send Forward command: {:?}", e);
+                            reply_failure(
+                                client.writer(),
+                                request_info.is_https,
+                                502,
+                                format!("tunnel [{}] unreachable", tunnel_id),
+                            )
+                            .await;
+                            return;
+                        }
+                        if want_result {
+                            let result = tokio::time::timeout(
+                                FORWARD_RESULT_TIMEOUT,
+                                TunnelCommandPacket::read_from(&mut upstream_reader),
+                            )
+                            .await;
+                            let failure = match result {
+                                Ok(Ok(packet)) => match packet.payload::<ForwardResult>() {
+                                    Ok(result) if result.ok => None,
+                                    Ok(result) => {
+                                        let error = result.error.unwrap_or(ForwardFailure::Other);
+                                        Some((
+                                            error.http_status(),
+                                            format!(
+                                                "tunnel [{}] target {:?}: {}",
+                                                tunnel_id,
+                                                error,
+                                                result.reason.unwrap_or_default()
+                                            ),
+                                        ))
+                                    }
+                                    Err(e) => Some((
+                                        502,
+                                        format!("tunnel [{}] invalid reply: {}", tunnel_id, e),
+                                    )),
+                                },
+                                Ok(Err(e)) => Some((
+                                    502,
+                                    format!("tunnel [{}] unreachable: {}", tunnel_id, e),
+                                )),
+                                Err(_) => Some((
+                                    504,
+                                    format!("tunnel [{}] did not answer in time", tunnel_id),
+                                )),
+                            };
+                            if let Some((status, message)) = failure {
+                                eprintln!("Forward failed: {}", message);
+                                reply_failure(
+                                    client.writer(),
+                                    request_info.is_https,
+                                    status,
+                                    message,
+                                )
+                                .await;
+                                return;
+                            }
+                        }
+
+                        if let Err(e) = client.relay(upstream_reader, upstream_writer).await {
+                            eprintln!("relay for tunnel [{}] aborted: {:?}", tunnel_id, e);
+                        }
                     } else {
-                        let _ = json_response(
-                            &mut tcp_send,
-                            &json!({
-                                "code": 404,
-                                "message": format!("tunnel [{}] not online", tunnel_id),
-                            }),
+                        // 503: no edge serves the tunnel, unlike the 502/504 of an
+                        // edge whose target is down.
+                        reply_failure(
+                            client.writer(),
+                            request_info.is_https,
+                            503,
+                            format!("tunnel [{}] not online", tunnel_id),
                         )
                         .await;
                     }
@@ -115,14 +385,71 @@ pub async fn bind_tcp_inbound(config: InboundConfig) -> Result<Arc<TcpInbound>,
     }
 }
 
-async fn json_response(
-    tcp_writer: &mut tokio::net::tcp::OwnedWriteHalf,
+/// Tells a public client that the tunnel could not serve it: an HTTP error for
+/// plain HTTP clients, a fatal TLS alert for clients that are mid-handshake.
+async fn reply_failure<W: AsyncWrite + Unpin + ?Sized>(
+    tcp_writer: &mut W,
+    is_https: bool,
+    status: u16,
+    message: String,
+) {
+    if is_https {
+        // alert record: fatal(2) internal_error(80)
+        let alert = [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 80];
+        let _ = tcp_writer.write_all(&alert).await;
+        let _ = tcp_writer.shutdown().await;
+        return;
+    }
+    let _ = json_response(
+        tcp_writer,
+        status,
+        &json!({
+            "code": status,
+            "message": message,
+        }),
+    )
+    .await;
+}
+
+pub async fn json_response<W: AsyncWrite + Unpin + ?Sized>(
+    tcp_writer: &mut W,
+    status: u16,
     body: &Value,
 ) -> anyhow::Result<()> {
     let body_str = serde_json::to_string(body)?;
+    http_response(
+        tcp_writer,
+        status,
+        "application/json; charset=utf-8",
+        &body_str,
+    )
+    .await
+}
+
+async fn http_response<W: AsyncWrite + Unpin + ?Sized>(
+    tcp_writer: &mut W,
+    status: u16,
+    content_type: &str,
+    body_str: &str,
+) -> anyhow::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Error",
+    };
     let response = format!(
-        "HTTP/1.1 200 OK\r\nContent-Type: application/json; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\nCache-Control: no-cache\r\n\r\n{}",
-        body_str.as_bytes().len(),
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\nCache-Control: no-cache\r\n\r\n{}",
+        status,
+        reason,
+        content_type,
+        body_str.len(),
         body_str
     );
     tcp_writer
@@ -135,3 +462,39 @@ async fn json_response(
         .unwrap_or_else(|e| eprintln!("[ERROR] Failed to shutdown TCP client: {}", e));
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn failure_reply(is_https: bool) -> Vec<u8> {
+        let (mut writer, mut reader) = tokio::io::duplex(1024);
+        reply_failure(
+            &mut writer,
+            is_https,
+            503,
+            "tunnel [web] not online".to_string(),
+        )
+        .await;
+        drop(writer);
+        let mut reply = Vec::new();
+        reader.read_to_end(&mut reply).await.unwrap();
+        reply
+    }
+
+    #[tokio::test]
+    async fn offline_tunnel_gets_an_error_status_or_alert() {
+        let reply = String::from_utf8(failure_reply(false).await).unwrap();
+        assert!(
+            reply.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{}",
+            reply
+        );
+        assert!(reply.ends_with(r#"{"code":503,"message":"tunnel [web] not online"}"#));
+        // A TLS client mid-handshake gets a fatal alert, not plaintext HTTP.
+        assert_eq!(
+            failure_reply(true).await,
+            [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 80]
+        );
+    }
+}
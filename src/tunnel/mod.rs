@@ -1,9 +1,20 @@
+pub mod acme;
+pub mod admin;
+pub mod authorizer;
+pub mod codec;
 pub mod common;
+pub mod control;
 pub mod edge;
+pub mod egress;
+pub mod error;
 pub mod constants;
 pub mod inbound;
 pub mod outbound;
 pub mod packet;
+pub mod payload;
+pub mod relay;
 pub mod session;
 pub mod sniff;
 pub mod supernode;
+pub mod token;
+pub mod version;
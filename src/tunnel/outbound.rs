@@ -1,38 +1,201 @@
-use crate::transport::base::TransportStream;
-use crate::tunnel::common::FORWARD_TO_KEY;
+use crate::transport::base::{TransportRecvStream, TransportSendStream};
+use crate::tunnel::common::FORWARD_CONNECT_TIMEOUT;
+use crate::tunnel::egress::{EgressPolicy, ForwardOverrides};
 use crate::tunnel::packet::TunnelCommandPacket;
-use tokio::io::{ReadHalf, WriteHalf};
+use crate::tunnel::payload::{ForwardFailure, ForwardRequest, ForwardResult};
+use crate::tunnel::relay::relay;
+use std::net::SocketAddr;
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 
+/// How the receiver of a `Forward` decides where to connect.
+pub enum ForwardTarget {
+    /// Where the sender asks, as far as the egress policy allows. Used by the supernode.
+    Requested(EgressPolicy),
+    /// The edge's `forward_to`, or an override the public client asked for if allowed.
+    Local {
+        forward_to: String,
+        overrides: ForwardOverrides,
+    },
+}
+
+impl ForwardTarget {
+    fn resolve(&self, request: &ForwardRequest) -> Result<String, ForwardResult> {
+        match self {
+            ForwardTarget::Requested(_) => Ok(request.target.clone()),
+            ForwardTarget::Local {
+                forward_to,
+                overrides,
+            } => match &request.override_target {
+                None => Ok(forward_to.clone()),
+                Some(requested) => overrides.resolve(requested).ok_or_else(|| {
+                    ForwardResult::failed(
+                        ForwardFailure::Forbidden,
+                        format!("forwarding to {} is not allowed", requested),
+                    )
+                }),
+            },
+        }
+    }
+
+    fn egress(&self) -> Option<&EgressPolicy> {
+        match self {
+            ForwardTarget::Requested(egress) => Some(egress),
+            ForwardTarget::Local { .. } => None,
+        }
+    }
+}
+
 pub async fn forward_to_tcp(
-    mut stream_reader: ReadHalf<Box<dyn TransportStream>>,
-    mut stream_writer: WriteHalf<Box<dyn TransportStream>>,
+    stream_reader: Box<dyn TransportRecvStream>,
+    mut stream_writer: Box<dyn TransportSendStream>,
     packet: TunnelCommandPacket,
-    default_forward_to: Option<String>,
+    target: ForwardTarget,
 ) -> anyhow::Result<()> {
-    let forward_target = match default_forward_to {
-        Some(forward_to) => forward_to,
-        None => match packet.meta.get(FORWARD_TO_KEY).and_then(|v| v.as_str()) {
-            Some(forward_to) => forward_to.to_string(),
-            None => "".to_string(),
-        },
+    let request = packet.payload::<ForwardRequest>()?;
+    let forward_target = match target.resolve(&request) {
+        Ok(forward_target) => forward_target,
+        Err(result) => {
+            let requested = request.override_target.clone().unwrap_or_default();
+            return Err(refuse(stream_writer, &packet, &request, result, &requested).await);
+        }
     };
     println!("[QUIC Client] Forwarding to: {}", forward_target);
-    let upstream = TcpStream::connect(forward_target).await?;
-    let (mut upstream_reader, mut upstream_writer) = tokio::io::split(upstream);
-    let stream_to_upstream = tokio::spawn(async move {
-        if let Err(e) = tokio::io::copy(&mut stream_reader, &mut upstream_writer).await {
-            eprintln!("[QUIC Client] copy stream -> upstream error: {:?}", e);
-        }
-    });
-    let upstream_to_stream = tokio::spawn(async move {
-        if let Err(e) = tokio::io::copy(&mut upstream_reader, &mut stream_writer).await {
-            eprintln!("[QUIC Client] copy upstream -> stream error: {:?}", e);
-        }
-    });
-    let res = tokio::try_join!(stream_to_upstream, upstream_to_stream);
-    if let Err(e) = res {
-        eprintln!("[QUIC Client] copy stream -> upstream error: {:?}", e);
+    let upstream = match connect_upstream(&forward_target, target.egress()).await {
+        Ok(upstream) => {
+            if request.want_result {
+                TunnelCommandPacket::from_payload(&ForwardResult::success())
+                    .with_encoding(packet.encoding)
+                    .write_to(&mut stream_writer)
+                    .await?;
+            }
+            upstream
+        }
+        Err(result) => {
+            return Err(refuse(stream_writer, &packet, &request, result, &forward_target).await);
+        }
+    };
+    let (upstream_reader, upstream_writer) = upstream.into_split();
+    if let Err(e) = relay(
+        upstream_reader,
+        upstream_writer,
+        stream_reader,
+        stream_writer,
+    )
+    .await
+    {
+        eprintln!("[QUIC Client] relay to {} aborted: {:?}", forward_target, e);
     }
     Ok(())
 }
+
+/// Reports a failed `Forward` to its sender and closes the stream.
+async fn refuse(
+    mut stream_writer: Box<dyn TransportSendStream>,
+    packet: &TunnelCommandPacket,
+    request: &ForwardRequest,
+    result: ForwardResult,
+    forward_target: &str,
+) -> anyhow::Error {
+    if request.want_result {
+        let _ = TunnelCommandPacket::from_payload(&result)
+            .with_encoding(packet.encoding)
+            .write_to(&mut stream_writer)
+            .await;
+    }
+    let _ = stream_writer.shutdown().await;
+    anyhow::anyhow!(
+        "Failed to connect to {}: {:?} {}",
+        forward_target,
+        result.error,
+        result.reason.unwrap_or_default()
+    )
+}
+
+/// Resolves and connects to `target`, classifying failures so they can be reported
+/// back to the side that sent the `Forward`. With an `egress` policy only the
+/// resolved addresses it allows are tried.
+async fn connect_upstream(
+    target: &str,
+    egress: Option<&EgressPolicy>,
+) -> Result<TcpStream, ForwardResult> {
+    let mut addrs: Vec<SocketAddr> = match tokio::time::timeout(
+        FORWARD_CONNECT_TIMEOUT,
+        tokio::net::lookup_host(target),
+    )
+    .await
+    {
+        Ok(Ok(addrs)) => addrs.collect(),
+        Ok(Err(e)) => {
+            return Err(ForwardResult::failed(
+                ForwardFailure::DnsFailure,
+                e.to_string(),
+            ));
+        }
+        Err(_) => {
+            return Err(ForwardResult::failed(
+                ForwardFailure::Timeout,
+                "DNS lookup timed out",
+            ));
+        }
+    };
+    if addrs.is_empty() {
+        return Err(ForwardResult::failed(
+            ForwardFailure::DnsFailure,
+            format!("{} did not resolve to any address", target),
+        ));
+    }
+    if let Some(egress) = egress {
+        addrs.retain(|addr| egress.allows(addr));
+        if addrs.is_empty() {
+            return Err(ForwardResult::failed(
+                ForwardFailure::Forbidden,
+                format!("{} is not allowed by the egress policy", target),
+            ));
+        }
+    }
+    match tokio::time::timeout(FORWARD_CONNECT_TIMEOUT, TcpStream::connect(&addrs[..])).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(e)) => Err(ForwardResult::failed(
+            ForwardFailure::from_io(&e),
+            e.to_string(),
+        )),
+        Err(_) => Err(ForwardResult::failed(
+            ForwardFailure::Timeout,
+            "connect timed out",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::base::TransportStream;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn egress_policy_refuses_private_targets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let request = ForwardRequest {
+            target: listener.local_addr().unwrap().to_string(),
+            want_result: true,
+            ..Default::default()
+        };
+        let (tunnel, mut peer) = tokio::io::duplex(1024);
+        let (stream_reader, stream_writer) = Box::new(tunnel).into_split();
+        let packet = TunnelCommandPacket::from_payload(&request);
+        let forward = forward_to_tcp(
+            stream_reader,
+            stream_writer,
+            packet,
+            ForwardTarget::Requested(EgressPolicy::default()),
+        );
+        assert!(forward.await.is_err());
+        let result = TunnelCommandPacket::read_from(&mut peer)
+            .await
+            .unwrap()
+            .payload::<ForwardResult>()
+            .unwrap();
+        assert_eq!(result.error, Some(ForwardFailure::Forbidden));
+    }
+}
@@ -1,13 +1,14 @@
-use crate::{
-    tunnel::common::{HEADER_FIXED_LEN, MAX_DATA_LEN},
-    transport::base::TransportStream,
-};
+use crate::tunnel::codec::TunnelCodec;
+use crate::tunnel::error::ProtocolError;
+use crate::tunnel::payload::{CommandPayload, ErrorReply};
+use bytes::BytesMut;
 use serde_json;
 use serde_json::Value;
 use std::collections::HashMap;
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::Encoder;
 #[repr(u8)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TunnelCommand {
     Ping = 0,
     Pong = 1,
@@ -15,149 +16,162 @@ pub enum TunnelCommand {
     AuthResult = 3,
     Forward = 4,
     SetSessionMeta = 5,
+    Error = 6,
+    SetSessionMetaResult = 7,
+    ForwardResult = 8,
+    Kick = 9,
+    Drain = 10,
+    Reconfigure = 11,
+    Message = 12,
+    AuthChallenge = 13,
+}
+
+impl TunnelCommand {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(TunnelCommand::Ping),
+            1 => Some(TunnelCommand::Pong),
+            2 => Some(TunnelCommand::Auth),
+            3 => Some(TunnelCommand::AuthResult),
+            4 => Some(TunnelCommand::Forward),
+            5 => Some(TunnelCommand::SetSessionMeta),
+            6 => Some(TunnelCommand::Error),
+            7 => Some(TunnelCommand::SetSessionMetaResult),
+            8 => Some(TunnelCommand::ForwardResult),
+            9 => Some(TunnelCommand::Kick),
+            10 => Some(TunnelCommand::Drain),
+            11 => Some(TunnelCommand::Reconfigure),
+            12 => Some(TunnelCommand::Message),
+            13 => Some(TunnelCommand::AuthChallenge),
+            _ => None,
+        }
+    }
+
+    /// Whether this command answers a request rather than starting one.
+    pub fn is_response(self) -> bool {
+        matches!(
+            self,
+            TunnelCommand::Pong
+                | TunnelCommand::AuthResult
+                | TunnelCommand::AuthChallenge
+                | TunnelCommand::SetSessionMetaResult
+                | TunnelCommand::ForwardResult
+                | TunnelCommand::Error
+        )
+    }
 }
 
 pub type TunnelMeta = HashMap<String, Value>;
 
+/// How the meta of a packet is serialized. JSON is what every peer understands;
+/// CBOR is only used once both sides advertised `CAP_META_CBOR` in the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetaEncoding {
+    #[default]
+    Json,
+    Cbor,
+}
+
 #[derive(Debug, Clone)]
 pub struct TunnelCommandPacket {
     pub command: TunnelCommand,
     pub length: u32,
     pub meta: TunnelMeta,
+    pub encoding: MetaEncoding,
 }
 
 impl TunnelCommandPacket {
     pub fn new(command: TunnelCommand, meta: &TunnelMeta) -> Self {
         Self {
-            command: command,
-            length: Self::encode_meta(&meta).len() as u32,
+            command,
+            length: Self::encode_meta(meta, MetaEncoding::Json).len() as u32,
             meta: meta.clone(),
+            encoding: MetaEncoding::Json,
         }
     }
 
-    pub fn encode_meta(meta: &TunnelMeta) -> Vec<u8> {
-        serde_json::to_vec(meta).unwrap()
+    pub fn with_encoding(mut self, encoding: MetaEncoding) -> Self {
+        self.encoding = encoding;
+        self.length = Self::encode_meta(&self.meta, encoding).len() as u32;
+        self
     }
 
-    pub fn decode_meta(bytes: &[u8]) -> TunnelMeta {
-        serde_json::from_slice(bytes).unwrap()
-    }
-
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(self.length as usize + HEADER_FIXED_LEN);
-        buf.extend_from_slice(&(self.command as u8).to_be_bytes());
-        buf.extend_from_slice(&self.length.to_be_bytes());
-        buf.extend_from_slice(&Self::encode_meta(&self.meta));
-        buf
-    }
-
-    pub async fn read_command2(
-        stream: &mut Box<dyn TransportStream>,
-    ) -> Result<Self, anyhow::Error> {
-        let mut buffer = [0u8; HEADER_FIXED_LEN];
-        stream.read_exact(&mut buffer).await?;
-        let (command, length) = (
-            buffer[0],
-            u32::from_be_bytes(buffer[1..HEADER_FIXED_LEN].try_into().unwrap()),
-        );
-        let command = match command as u8 {
-            0 => TunnelCommand::Ping,
-            1 => TunnelCommand::Pong,
-            2 => TunnelCommand::Auth,
-            3 => TunnelCommand::AuthResult,
-            4 => TunnelCommand::Forward,
-            _ => return Err(anyhow::anyhow!("Invalid command type")),
+    pub fn from_payload<P: CommandPayload>(payload: &P) -> Self {
+        let meta = match serde_json::to_value(payload) {
+            Ok(Value::Object(map)) => map.into_iter().collect(),
+            _ => TunnelMeta::new(),
         };
+        Self::new(P::COMMAND, &meta)
+    }
 
-        if length > MAX_DATA_LEN as u32 {
-            return Err(anyhow::anyhow!("Data length is too large {}", length));
+    pub fn payload<P: CommandPayload>(&self) -> Result<P, ProtocolError> {
+        if self.command != P::COMMAND {
+            return Err(ProtocolError::UnexpectedCommand(self.command as u8));
         }
-
-        let mut data_buffer = vec![0u8; length as usize];
-        stream.read_exact(&mut data_buffer).await?;
-
-        let result = Self {
-            command,
-            length,
-            meta: Self::decode_meta(&data_buffer),
-        };
-        Ok(result)
+        let map = self.meta.clone().into_iter().collect();
+        Ok(serde_json::from_value(Value::Object(map))?)
     }
 
-    pub async fn read_command1(
-        reader: &mut (dyn AsyncRead + Unpin + Send),
-    ) -> Result<Self, anyhow::Error> {
-        let mut buffer = [0u8; HEADER_FIXED_LEN];
-        reader.read_exact(&mut buffer).await?;
-        let (command, length) = (
-            buffer[0],
-            u32::from_be_bytes(buffer[1..HEADER_FIXED_LEN].try_into().unwrap()),
-        );
-        let command = match command as u8 {
-            0 => TunnelCommand::Ping,
-            1 => TunnelCommand::Pong,
-            2 => TunnelCommand::Auth,
-            3 => TunnelCommand::AuthResult,
-            4 => TunnelCommand::Forward,
-            _ => return Err(anyhow::anyhow!("Invalid command type")),
-        };
+    pub fn error(error: &ProtocolError) -> Self {
+        Self::from_payload(&ErrorReply::from(error))
+    }
 
-        if length > MAX_DATA_LEN as u32 {
-            return Err(anyhow::anyhow!("Data length is too large {}", length));
+    pub fn encode_meta(meta: &TunnelMeta, encoding: MetaEncoding) -> Vec<u8> {
+        match encoding {
+            MetaEncoding::Json => {
+                serde_json::to_vec(meta).expect("string-keyed JSON map always serializes")
+            }
+            MetaEncoding::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(meta, &mut buf).expect("writing CBOR to memory cannot fail");
+                buf
+            }
         }
-
-        let mut data_buffer = vec![0u8; length as usize];
-        reader.read_exact(&mut data_buffer).await?;
-
-        let result = Self {
-            command,
-            length,
-            meta: Self::decode_meta(&data_buffer),
-        };
-        Ok(result)
     }
 
-    pub async fn read_command(stream: &mut quinn::RecvStream) -> Result<Self, anyhow::Error> {
-        let mut buffer = [0u8; HEADER_FIXED_LEN];
-        let result = stream.read_exact(&mut buffer);
-        let (command, length) = match result.await {
-            Ok(()) => (
-                buffer[0],
-                u32::from_be_bytes(buffer[1..HEADER_FIXED_LEN].try_into().unwrap()),
-            ),
-            Err(e) => {
-                eprintln!("[ERROR] Error reading stream: {:?}", e);
-                return Err(anyhow::anyhow!("Error reading stream"));
+    pub fn decode_meta(bytes: &[u8], encoding: MetaEncoding) -> Result<TunnelMeta, ProtocolError> {
+        match encoding {
+            MetaEncoding::Json => Ok(serde_json::from_slice(bytes)?),
+            MetaEncoding::Cbor => {
+                ciborium::from_reader(bytes).map_err(|e| ProtocolError::InvalidMeta(e.to_string()))
             }
-        };
-        let command = match command as u8 {
-            0 => TunnelCommand::Ping,
-            1 => TunnelCommand::Pong,
-            2 => TunnelCommand::Auth,
-            3 => TunnelCommand::AuthResult,
-            4 => TunnelCommand::Forward,
-            _ => return Err(anyhow::anyhow!("Invalid command type")),
-        };
+        }
+    }
 
-        if length > MAX_DATA_LEN as u32 {
-            return Err(anyhow::anyhow!("Data length is too large {}", length));
+    /// Turns an `Error` reply from the peer into an `Err`, passing anything else through.
+    pub fn into_result(self) -> anyhow::Result<Self> {
+        if self.command == TunnelCommand::Error {
+            let reply = self.payload::<ErrorReply>()?;
+            return Err(anyhow::anyhow!(
+                "Peer error {}: {}",
+                reply.code,
+                reply.reason
+            ));
         }
+        Ok(self)
+    }
 
-        let mut data_buffer = vec![0u8; length as usize];
-        let result = stream.read_exact(&mut data_buffer);
-        let data = match result.await {
-            Ok(()) => Self::decode_meta(&data_buffer),
-            Err(e) => {
-                eprintln!("[ERROR] Error reading stream: {:?}", e);
-                return Err(anyhow::anyhow!("Error reading stream"));
-            }
-        };
+    /// Fails with `DataTooLarge` when the meta exceeds the local limit.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut buf = BytesMut::new();
+        TunnelCodec::default().encode(self, &mut buf)?;
+        Ok(buf.to_vec())
+    }
 
-        let result = Self {
-            command,
-            length,
-            meta: data,
-        };
-        Ok(result)
+    /// Reads exactly one packet, leaving any bytes that follow it (e.g. the
+    /// relayed payload after a `Forward`) unread in `reader`.
+    pub async fn read_from<R>(reader: &mut R) -> Result<Self, ProtocolError>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        TunnelCodec::default().read_packet(reader).await
+    }
+
+    pub async fn write_to<W>(&self, writer: &mut W) -> Result<(), anyhow::Error>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        writer.write_all(&self.to_bytes()?).await?;
+        Ok(())
     }
 }
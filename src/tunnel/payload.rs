@@ -0,0 +1,508 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::tunnel::codec::max_data_len;
+use crate::tunnel::error::ProtocolError;
+use crate::tunnel::packet::{TunnelCommand, TunnelMeta};
+use crate::tunnel::version::{CAPABILITIES, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, ProtocolInfo};
+
+/// Typed body of a command packet. Every payload keeps the fields it does not
+/// know about in `extra`, so newer peers can add fields without breaking us.
+pub trait CommandPayload: Serialize + DeserializeOwned {
+    const COMMAND: TunnelCommand;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AuthRequest {
+    /// The token itself, only sent by edges using legacy auth.
+    #[serde(
+        rename = "X-Tunnel-Token",
+        default,
+        skip_serializing_if = "String::is_empty"
+    )]
+    pub token: String,
+    /// Asks for an `AuthChallenge` instead of sending the token (see `token_id`).
+    #[serde(
+        rename = "X-Tunnel-Token-Id",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub token_id: Option<String>,
+    /// Answer to an `AuthChallenge`, base64url (see `auth_proof`).
+    #[serde(
+        rename = "X-Tunnel-Auth-Proof",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub proof: Option<String>,
+    #[serde(
+        rename = "X-Tunnel-Version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub version: Option<u32>,
+    #[serde(
+        rename = "X-Tunnel-Min-Version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub min_version: Option<u32>,
+    #[serde(rename = "X-Tunnel-Capabilities", default)]
+    pub capabilities: Vec<String>,
+    #[serde(
+        rename = "X-Tunnel-Max-Data-Len",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_data_len: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(flatten)]
+    pub extra: TunnelMeta,
+}
+
+impl AuthRequest {
+    pub fn new(token: &str) -> Self {
+        Self {
+            token: token.to_string(),
+            token_id: None,
+            proof: None,
+            version: Some(PROTOCOL_VERSION),
+            min_version: Some(MIN_PROTOCOL_VERSION),
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            max_data_len: Some(max_data_len() as u32),
+            hostname: hostname::get()
+                .ok()
+                .map(|h| h.to_string_lossy().to_string()),
+            extra: TunnelMeta::new(),
+        }
+    }
+
+    pub fn protocol(&self) -> anyhow::Result<ProtocolInfo> {
+        ProtocolInfo::negotiate(
+            self.version,
+            self.min_version,
+            &self.capabilities,
+            self.max_data_len,
+        )
+    }
+}
+
+impl CommandPayload for AuthRequest {
+    const COMMAND: TunnelCommand = TunnelCommand::Auth;
+}
+
+/// Answer to an `Auth` that carries a token id. The edge replies with another
+/// `Auth` holding the proof for `nonce`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AuthChallenge {
+    /// Random bytes, base64url.
+    pub nonce: String,
+    #[serde(flatten)]
+    pub extra: TunnelMeta,
+}
+
+impl CommandPayload for AuthChallenge {
+    const COMMAND: TunnelCommand = TunnelCommand::AuthChallenge;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AuthResult {
+    #[serde(rename = "result")]
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_url: Option<String>,
+    #[serde(
+        rename = "X-Tunnel-Version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub version: Option<u32>,
+    #[serde(rename = "X-Tunnel-Capabilities", default)]
+    pub capabilities: Vec<String>,
+    #[serde(
+        rename = "X-Tunnel-Max-Data-Len",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_data_len: Option<u32>,
+    #[serde(flatten)]
+    pub extra: TunnelMeta,
+}
+
+impl AuthResult {
+    pub fn accepted(protocol: &ProtocolInfo) -> Self {
+        Self {
+            ok: true,
+            version: Some(protocol.version),
+            capabilities: protocol.capabilities.clone(),
+            max_data_len: Some(max_data_len() as u32),
+            ..Default::default()
+        }
+    }
+
+    pub fn rejected(reason: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            reason: Some(reason.into()),
+            ..Default::default()
+        }
+    }
+
+    pub fn protocol(&self) -> anyhow::Result<ProtocolInfo> {
+        ProtocolInfo::negotiate(self.version, None, &self.capabilities, self.max_data_len)
+    }
+}
+
+impl CommandPayload for AuthResult {
+    const COMMAND: TunnelCommand = TunnelCommand::AuthResult;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ForwardRequest {
+    #[serde(rename = "X-Tunnel-Forward-To", default)]
+    pub target: String,
+    /// Target the public client asked for with `X-Tunnel-Forward-To`. Edges
+    /// only connect to it when their `ForwardOverrides` allow it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub override_target: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    /// Asks the receiver to answer with a `ForwardResult` before relaying any bytes.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub want_result: bool,
+    #[serde(flatten)]
+    pub extra: TunnelMeta,
+}
+
+impl CommandPayload for ForwardRequest {
+    const COMMAND: TunnelCommand = TunnelCommand::Forward;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardFailure {
+    ConnectionRefused,
+    DnsFailure,
+    Timeout,
+    Unreachable,
+    /// Refused by the receiver's policy.
+    Forbidden,
+    #[serde(other)]
+    Other,
+}
+
+impl ForwardFailure {
+    pub fn from_io(error: &std::io::Error) -> Self {
+        match error.kind() {
+            std::io::ErrorKind::ConnectionRefused => ForwardFailure::ConnectionRefused,
+            std::io::ErrorKind::TimedOut => ForwardFailure::Timeout,
+            std::io::ErrorKind::HostUnreachable | std::io::ErrorKind::NetworkUnreachable => {
+                ForwardFailure::Unreachable
+            }
+            _ => ForwardFailure::Other,
+        }
+    }
+
+    /// Status a public HTTP client gets when the target could not be reached.
+    pub fn http_status(&self) -> u16 {
+        match self {
+            ForwardFailure::Timeout => 504,
+            ForwardFailure::Forbidden => 403,
+            _ => 502,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ForwardResult {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ForwardFailure>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(flatten)]
+    pub extra: TunnelMeta,
+}
+
+impl ForwardResult {
+    pub fn success() -> Self {
+        Self {
+            ok: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn failed(error: ForwardFailure, reason: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            error: Some(error),
+            reason: Some(reason.into()),
+            ..Default::default()
+        }
+    }
+}
+
+impl CommandPayload for ForwardResult {
+    const COMMAND: TunnelCommand = TunnelCommand::ForwardResult;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Ping {
+    #[serde(default)]
+    pub seq: u64,
+    /// Milliseconds since the Unix epoch on the sender's clock.
+    #[serde(default)]
+    pub sent_at: u64,
+    /// Identifies the session for peers that open a new stream per ping.
+    #[serde(
+        rename = "X-Tunnel-Token",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub token: Option<String>,
+    #[serde(flatten)]
+    pub extra: TunnelMeta,
+}
+
+impl CommandPayload for Ping {
+    const COMMAND: TunnelCommand = TunnelCommand::Ping;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Pong {
+    #[serde(default)]
+    pub seq: u64,
+    /// Echo of `Ping::sent_at`, so the pinger can measure the round trip.
+    #[serde(default)]
+    pub sent_at: u64,
+    #[serde(flatten)]
+    pub extra: TunnelMeta,
+}
+
+impl From<&Ping> for Pong {
+    fn from(ping: &Ping) -> Self {
+        Self {
+            seq: ping.seq,
+            sent_at: ping.sent_at,
+            extra: TunnelMeta::new(),
+        }
+    }
+}
+
+impl CommandPayload for Pong {
+    const COMMAND: TunnelCommand = TunnelCommand::Pong;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExposedService {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+/// Partial update of the session metadata; fields left as `None` keep their current value.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionMetaUpdate {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub services: Option<Vec<ExposedService>>,
+    #[serde(flatten)]
+    pub extra: TunnelMeta,
+}
+
+impl SessionMetaUpdate {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Overlays the fields set in `other` onto `self`.
+    pub fn merge(&mut self, other: SessionMetaUpdate) {
+        if other.display_name.is_some() {
+            self.display_name = other.display_name;
+        }
+        if other.device_name.is_some() {
+            self.device_name = other.device_name;
+        }
+        if other.tags.is_some() {
+            self.tags = other.tags;
+        }
+        if other.services.is_some() {
+            self.services = other.services;
+        }
+        self.extra.extend(other.extra);
+    }
+
+    /// Writes the fields that are set into a session's meta map.
+    pub fn apply(&self, meta: &mut TunnelMeta) {
+        if let Ok(serde_json::Value::Object(map)) = serde_json::to_value(self) {
+            meta.extend(map);
+        }
+    }
+}
+
+impl CommandPayload for SessionMetaUpdate {
+    const COMMAND: TunnelCommand = TunnelCommand::SetSessionMeta;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionMetaResult {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// The session meta after the update was merged in.
+    #[serde(default)]
+    pub meta: TunnelMeta,
+}
+
+impl CommandPayload for SessionMetaResult {
+    const COMMAND: TunnelCommand = TunnelCommand::SetSessionMetaResult;
+}
+
+/// Disconnects an edge. It must not reconnect before `retry_after` seconds have passed.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Kick {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub retry_after: u64,
+    #[serde(flatten)]
+    pub extra: TunnelMeta,
+}
+
+impl CommandPayload for Kick {
+    const COMMAND: TunnelCommand = TunnelCommand::Kick;
+}
+
+/// Asks an edge to reconnect, to `server_addr` when set or to the same supernode otherwise.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Drain {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_addr: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(flatten)]
+    pub extra: TunnelMeta,
+}
+
+impl CommandPayload for Drain {
+    const COMMAND: TunnelCommand = TunnelCommand::Drain;
+}
+
+/// Changes edge settings at runtime; fields left as `None` are kept.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Reconfigure {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_to: Option<String>,
+    /// Seconds between two pings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heartbeat_interval: Option<u64>,
+    #[serde(flatten)]
+    pub extra: TunnelMeta,
+}
+
+impl CommandPayload for Reconfigure {
+    const COMMAND: TunnelCommand = TunnelCommand::Reconfigure;
+}
+
+/// Free-form notice from the operator, shown to the edge user.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    #[serde(default)]
+    pub text: String,
+    #[serde(flatten)]
+    pub extra: TunnelMeta,
+}
+
+impl CommandPayload for Message {
+    const COMMAND: TunnelCommand = TunnelCommand::Message;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ErrorReply {
+    #[serde(default)]
+    pub code: String,
+    #[serde(default)]
+    pub reason: String,
+    #[serde(flatten)]
+    pub extra: TunnelMeta,
+}
+
+impl From<&ProtocolError> for ErrorReply {
+    fn from(error: &ProtocolError) -> Self {
+        Self {
+            code: error.code().to_string(),
+            reason: error.to_string(),
+            extra: TunnelMeta::new(),
+        }
+    }
+}
+
+impl CommandPayload for ErrorReply {
+    const COMMAND: TunnelCommand = TunnelCommand::Error;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::packet::TunnelCommandPacket;
+    use serde_json::Value;
+
+    #[test]
+    fn legacy_wire_keys_are_preserved() {
+        let packet = TunnelCommandPacket::new(
+            TunnelCommand::Forward,
+            &TunnelMeta::from([("X-Tunnel-Forward-To".to_string(), Value::from("a:80"))]),
+        );
+        assert_eq!(packet.payload::<ForwardRequest>().unwrap().target, "a:80");
+
+        let packet = TunnelCommandPacket::from_payload(&AuthResult::rejected("nope"));
+        assert_eq!(packet.meta.get("result"), Some(&Value::Bool(false)));
+    }
+
+    #[test]
+    fn unknown_fields_round_trip() {
+        let meta = TunnelMeta::from([
+            ("seq".to_string(), Value::from(7)),
+            ("sent_at".to_string(), Value::from(0)),
+            ("future_field".to_string(), Value::from("x")),
+        ]);
+        let ping = TunnelCommandPacket::new(TunnelCommand::Ping, &meta)
+            .payload::<Ping>()
+            .unwrap();
+        assert_eq!(ping.seq, 7);
+        assert_eq!(ping.extra.get("future_field"), Some(&Value::from("x")));
+        assert_eq!(TunnelCommandPacket::from_payload(&ping).meta, meta);
+    }
+
+    #[test]
+    fn challenge_auth_omits_token() {
+        let request = AuthRequest {
+            token_id: Some("sha256:abc".to_string()),
+            ..Default::default()
+        };
+        let packet = TunnelCommandPacket::from_payload(&request);
+        assert!(!packet.meta.contains_key("X-Tunnel-Token"));
+        assert_eq!(packet.payload::<AuthRequest>().unwrap(), request);
+    }
+
+    #[test]
+    fn payload_checks_command() {
+        let packet = TunnelCommandPacket::from_payload(&Ping::default());
+        assert!(matches!(
+            packet.payload::<Pong>(),
+            Err(ProtocolError::UnexpectedCommand(0))
+        ));
+    }
+}
@@ -0,0 +1,218 @@
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+use crate::transport::base::{TransportRecvStream, TransportSendStream};
+
+/// Stream error code used when the TCP connection on the other end of the tunnel was reset.
+pub const RESET_CODE_CONNECTION_RESET: u32 = 1;
+/// Stream error code used for any other failure that aborted the relay.
+pub const RESET_CODE_ABORTED: u32 = 2;
+
+/// Copies between a TCP connection and a tunnel stream until both directions
+/// are done. EOF is passed on as a half-close (TCP `shutdown(Write)` / QUIC
+/// finish) while the other direction keeps flowing. A reset or failure on
+/// either side aborts both: the TCP socket is closed with RST and the stream is
+/// reset and stopped with an error code.
+pub async fn relay(
+    mut tcp_reader: OwnedReadHalf,
+    mut tcp_writer: OwnedWriteHalf,
+    stream_reader: Box<dyn TransportRecvStream>,
+    stream_writer: Box<dyn TransportSendStream>,
+) -> io::Result<()> {
+    let result = relay_io(
+        &mut tcp_reader,
+        &mut tcp_writer,
+        stream_reader,
+        stream_writer,
+    )
+    .await;
+    if result.is_err()
+        && let Ok(tcp) = tcp_reader.reunite(tcp_writer)
+    {
+        // Dropping a socket with a zero linger sends RST instead of FIN.
+        let _ = tcp.set_linger(Some(Duration::ZERO));
+    }
+    result
+}
+
+/// `relay` for a connection that is not a bare TCP socket, such as a TLS
+/// connection terminated by the supernode. A failure only resets the stream.
+pub async fn relay_io<R, W>(
+    mut reader: R,
+    mut writer: W,
+    mut stream_reader: Box<dyn TransportRecvStream>,
+    mut stream_writer: Box<dyn TransportSendStream>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let result = {
+        let upload = pipe(&mut reader, &mut stream_writer);
+        let download = pipe(&mut stream_reader, &mut writer);
+        tokio::pin!(upload, download);
+        tokio::select! {
+            result = &mut upload => match result {
+                Ok(()) => download.await,
+                Err(e) => Err(e),
+            },
+            result = &mut download => match result {
+                Ok(()) => upload.await,
+                Err(e) => Err(e),
+            },
+        }
+    };
+    if let Err(e) = &result {
+        let code = match e.kind() {
+            io::ErrorKind::ConnectionReset => RESET_CODE_CONNECTION_RESET,
+            _ => RESET_CODE_ABORTED,
+        };
+        stream_writer.reset(code);
+        stream_reader.stop(code);
+    }
+    result
+}
+
+/// Copies one direction and half-closes the writer once the reader is done.
+async fn pipe<R, W>(reader: &mut R, writer: &mut W) -> io::Result<()>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    tokio::io::copy(reader, writer).await?;
+    writer.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::base::TransportStream;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncReadExt, DuplexStream, ReadBuf, ReadHalf, WriteHalf};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Send half that remembers the code it was reset with.
+    struct RecordingSend(WriteHalf<DuplexStream>, Arc<Mutex<Option<u32>>>);
+
+    impl AsyncWrite for RecordingSend {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+
+    impl TransportSendStream for RecordingSend {
+        fn reset(&mut self, code: u32) {
+            *self.1.lock().unwrap() = Some(code);
+        }
+    }
+
+    /// Receive half whose peer going away reads as a stream reset, like a
+    /// QUIC `RESET_STREAM`, instead of a finish.
+    struct ResetOnEof(ReadHalf<DuplexStream>);
+
+    impl AsyncRead for ResetOnEof {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let filled = buf.filled().len();
+            match Pin::new(&mut self.0).poll_read(cx, buf) {
+                Poll::Ready(Ok(())) if buf.filled().len() == filled => {
+                    Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+                }
+                poll => poll,
+            }
+        }
+    }
+
+    impl TransportRecvStream for ResetOnEof {}
+
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn half_close_keeps_other_direction_open() {
+        let (mut client, server) = tcp_pair().await;
+        let (tunnel, mut peer) = tokio::io::duplex(1024);
+        let (tcp_reader, tcp_writer) = server.into_split();
+        let (stream_reader, stream_writer) = Box::new(tunnel).into_split();
+        let relay = tokio::spawn(relay(tcp_reader, tcp_writer, stream_reader, stream_writer));
+
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        peer.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+
+        peer.write_all(b"response").await.unwrap();
+        peer.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+        relay.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn tcp_reset_resets_the_stream() {
+        let (client, server) = tcp_pair().await;
+        let (tunnel, _peer) = tokio::io::duplex(1024);
+        let (tcp_reader, tcp_writer) = server.into_split();
+        let (stream_reader, stream_writer) = tokio::io::split(tunnel);
+        let reset = Arc::new(Mutex::new(None));
+        let relay = tokio::spawn(relay(
+            tcp_reader,
+            tcp_writer,
+            Box::new(stream_reader),
+            Box::new(RecordingSend(stream_writer, reset.clone())),
+        ));
+
+        client.set_linger(Some(Duration::ZERO)).unwrap();
+        drop(client);
+        let result = relay.await.unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(*reset.lock().unwrap(), Some(RESET_CODE_CONNECTION_RESET));
+    }
+
+    #[tokio::test]
+    async fn stream_reset_resets_the_tcp_connection() {
+        let (mut client, server) = tcp_pair().await;
+        let (tunnel, peer) = tokio::io::duplex(1024);
+        let (tcp_reader, tcp_writer) = server.into_split();
+        let (stream_reader, stream_writer) = tokio::io::split(tunnel);
+        let relay = tokio::spawn(relay(
+            tcp_reader,
+            tcp_writer,
+            Box::new(ResetOnEof(stream_reader)),
+            Box::new(stream_writer),
+        ));
+
+        drop(peer);
+        assert!(relay.await.unwrap().is_err());
+        let mut buf = Vec::new();
+        let read = client.read_to_end(&mut buf).await;
+        assert_eq!(read.unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    }
+}
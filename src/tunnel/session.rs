@@ -1,24 +1,105 @@
 use std::{
     collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
     sync::{Arc, LazyLock},
+    time::Duration,
 };
 
 use dashmap::DashMap;
 use serde_json::Value;
 
 use crate::transport::base::TransportConnection;
+use crate::tunnel::control::ControlChannel;
+use crate::tunnel::egress::EgressPolicy;
+use crate::tunnel::token::TokenLimits;
+use crate::tunnel::version::ProtocolInfo;
 
 pub const DEFAULT_CLIENT_ID: &str = "default_client_id";
 
 #[derive(Clone)]
 pub struct TransportSession {
     pub conn: Arc<dyn TransportConnection + Send + Sync + 'static>,
+    /// Same as the session id, except for the members of a group, whose ids
+    /// carry a suffix.
+    pub client_id: String,
     pub meta: HashMap<String, Value>,
     pub ping_at: tokio::time::Instant,
+    pub protocol: ProtocolInfo,
+    /// `token_id` of the token the session authenticated with, so revoking
+    /// the token can find it. `None` for certificate-only sessions.
+    pub token_id: Option<String>,
+    pub control: Option<Arc<ControlChannel>>,
+    /// Public tunnel names routed to this session.
+    pub tunnels: Vec<String>,
+    pub limits: TokenLimits,
+    /// Egress policy of the session's token, overriding the global one.
+    pub egress: Option<EgressPolicy>,
+    /// Public connections currently forwarded to this session.
+    pub active_streams: Arc<AtomicUsize>,
+}
+
+impl TransportSession {
+    /// Counts a forwarded connection against `limits.max_streams` until the
+    /// guard is dropped. `None` when the limit is reached.
+    pub fn acquire_stream(&self) -> Option<StreamGuard> {
+        let max = self
+            .limits
+            .max_streams
+            .map_or(usize::MAX, |max| max as usize);
+        self.active_streams
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < max).then_some(active + 1)
+            })
+            .ok()?;
+        Some(StreamGuard(self.active_streams.clone()))
+    }
+}
+
+pub struct StreamGuard(Arc<AtomicUsize>);
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
 }
 
 pub static TRANSPORT_SESSION_MAP: LazyLock<DashMap<String, TransportSession>> =
-    LazyLock::new(|| DashMap::new());
+    LazyLock::new(DashMap::new);
+
+/// Tunnel name to the ids of the sessions serving it. There is more than one
+/// only when several edges share a client id as a group.
+pub static TUNNEL_ROUTE_MAP: LazyLock<DashMap<String, Vec<String>>> = LazyLock::new(DashMap::new);
+
+/// Routes `tunnel` to `session_id` as well, forgetting sessions that are gone.
+pub fn add_route(tunnel: &str, session_id: &str) {
+    let mut ids = TUNNEL_ROUTE_MAP.entry(tunnel.to_string()).or_default();
+    ids.retain(|id| id != session_id && TRANSPORT_SESSION_MAP.contains_key(id));
+    ids.push(session_id.to_string());
+}
+
+static NEXT_ROUTE: AtomicUsize = AtomicUsize::new(0);
+
+/// Id of the session to forward a connection for `tunnel` to: of the sessions
+/// allowed to serve it, the one with the fewest open streams, taking turns on ties.
+pub fn resolve_tunnel(tunnel: &str) -> Option<String> {
+    let mut ids = TUNNEL_ROUTE_MAP.get(tunnel)?.value().clone();
+    if ids.len() > 1 {
+        let len = ids.len();
+        ids.rotate_left(NEXT_ROUTE.fetch_add(1, Ordering::Relaxed) % len);
+    }
+    ids.into_iter()
+        .filter_map(|id| {
+            let session = TRANSPORT_SESSION_MAP.get(&id)?;
+            let active = session.active_streams.load(Ordering::Relaxed);
+            session
+                .tunnels
+                .iter()
+                .any(|t| t == tunnel)
+                .then_some((id, active))
+        })
+        .min_by_key(|(_, active)| *active)
+        .map(|(id, _)| id)
+}
 
 pub fn get_session(id: &str) -> Option<TransportSession> {
     TRANSPORT_SESSION_MAP
@@ -26,15 +107,135 @@ pub fn get_session(id: &str) -> Option<TransportSession> {
         .map(|session| session.value().clone())
 }
 
+/// Key of the session that was registered for the given connection.
+pub fn find_session_id_by_conn(conn_id: usize) -> Option<String> {
+    TRANSPORT_SESSION_MAP
+        .iter()
+        .find(|session| session.value().conn.id() == conn_id)
+        .map(|session| session.key().clone())
+}
+
 pub fn get_default_session() -> Option<TransportSession> {
     get_session(DEFAULT_CLIENT_ID)
 }
 
+/// Sessions that have not pinged for this long are dropped.
+pub const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
 pub async fn clear_expired_sessions() {
     TRANSPORT_SESSION_MAP
         .iter()
-        .filter(|session| session.value().ping_at.elapsed().as_secs() > 60)
+        .filter(|session| session.value().ping_at.elapsed() > SESSION_TIMEOUT)
         .for_each(|session| {
             TRANSPORT_SESSION_MAP.remove(session.key());
         });
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::transport::base::{TransportKind, TransportStream};
+    use rustls::pki_types::CertificateDer;
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+
+    /// A connection that only records how it was closed.
+    #[derive(Default)]
+    pub(crate) struct FakeConnection {
+        id: usize,
+        pub(crate) closed: Mutex<Option<(u32, String)>>,
+    }
+
+    #[async_trait::async_trait]
+    impl TransportConnection for FakeConnection {
+        fn kind(&self) -> TransportKind {
+            TransportKind::QUIC
+        }
+
+        fn id(&self) -> usize {
+            self.id
+        }
+
+        async fn open_stream(&self) -> anyhow::Result<Box<dyn TransportStream>> {
+            Err(anyhow::anyhow!("no streams"))
+        }
+
+        fn close(&self, code: u32, reason: &str) {
+            *self.closed.lock().unwrap() = Some((code, reason.to_string()));
+        }
+
+        fn export_keying_material(
+            &self,
+            _output: &mut [u8],
+            _label: &[u8],
+            _context: &[u8],
+        ) -> anyhow::Result<()> {
+            Err(anyhow::anyhow!("no TLS session"))
+        }
+
+        fn peer_certificates(&self) -> Option<Vec<CertificateDer<'static>>> {
+            None
+        }
+
+        fn remote_addr(&self) -> SocketAddr {
+            "192.0.2.1:5000".parse().unwrap()
+        }
+
+        fn peer_close(&self) -> Option<(u32, String)> {
+            None
+        }
+    }
+
+    /// A session of `client_id` serving the tunnel of the same name, on a new fake connection.
+    pub(crate) fn fake_session(client_id: &str) -> (TransportSession, Arc<FakeConnection>) {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+        let conn = Arc::new(FakeConnection {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            ..Default::default()
+        });
+        let session = TransportSession {
+            conn: conn.clone(),
+            client_id: client_id.to_string(),
+            meta: HashMap::new(),
+            ping_at: tokio::time::Instant::now(),
+            protocol: ProtocolInfo::legacy(),
+            token_id: None,
+            control: None,
+            tunnels: vec![client_id.to_string()],
+            limits: TokenLimits::default(),
+            egress: None,
+            active_streams: Arc::new(AtomicUsize::new(0)),
+        };
+        (session, conn)
+    }
+
+    #[test]
+    fn tunnel_goes_to_the_least_busy_session() {
+        let (busy, _) = fake_session("route-test");
+        let (idle, _) = fake_session("route-test");
+        busy.active_streams.store(3, Ordering::Relaxed);
+        idle.active_streams.store(1, Ordering::Relaxed);
+        TRANSPORT_SESSION_MAP.insert("route-test".to_string(), busy.clone());
+        TRANSPORT_SESSION_MAP.insert("route-test#2".to_string(), idle.clone());
+        add_route("route-test", "route-test");
+        add_route("route-test", "route-test#2");
+
+        for _ in 0..4 {
+            assert_eq!(
+                resolve_tunnel("route-test").as_deref(),
+                Some("route-test#2")
+            );
+        }
+        // On a tie, both take turns.
+        busy.active_streams.store(1, Ordering::Relaxed);
+        let picked: std::collections::HashSet<_> = (0..4)
+            .filter_map(|_| resolve_tunnel("route-test"))
+            .collect();
+        assert_eq!(picked.len(), 2);
+        // Sessions that are gone are not picked.
+        TRANSPORT_SESSION_MAP.remove("route-test#2");
+        assert_eq!(resolve_tunnel("route-test").as_deref(), Some("route-test"));
+        assert_eq!(resolve_tunnel("no-such-tunnel"), None);
+        TRANSPORT_SESSION_MAP.remove("route-test");
+    }
+}
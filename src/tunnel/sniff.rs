@@ -5,13 +5,17 @@ use std::collections::HashMap;
 use std::io::Cursor;
 use tokio::net::tcp::OwnedReadHalf;
 
-use crate::tunnel::common::{AUTH_TOKEN_KEY, get_client_id_from_token};
+use crate::tunnel::common::{AUTH_TOKEN_KEY, FORWARD_TO_KEY, get_client_id_from_token};
 
 #[derive(Debug)]
 pub struct SniffResult {
     pub tunnel_id: String,
     pub host: String,
     pub is_https: bool,
+    /// Value of the `X-Tunnel-Forward-To` header, if the client sent one.
+    pub forward_to: Option<String>,
+    /// Request path of a plain HTTP request.
+    pub path: Option<String>,
 }
 
 pub async fn sniff_tcp(tcp_stream: &mut OwnedReadHalf) -> Result<SniffResult> {
@@ -20,11 +24,14 @@ pub async fn sniff_tcp(tcp_stream: &mut OwnedReadHalf) -> Result<SniffResult> {
     if n == 0 {
         return Err(anyhow::anyhow!("No data available"));
     }
-    let data = &peek_buffer[..n];
+    sniff_bytes(&peek_buffer[..n])
+}
+
+pub fn sniff_bytes(data: &[u8]) -> Result<SniffResult> {
     if let Some(result) = sniff_http(data) {
         return Ok(result);
     }
-    if let Some(result) = sniff_tls_sni_safe(&peek_buffer[..n])? {
+    if let Some(result) = sniff_tls_sni_safe(data)? {
         return Ok(result);
     }
 
@@ -59,15 +66,23 @@ fn sniff_http(buf: &[u8]) -> Option<SniffResult> {
                 .to_string(),
         };
 
+        let forward_to = request
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(FORWARD_TO_KEY))
+            .map(|h| String::from_utf8_lossy(h.value).trim().to_string());
+
         let host = if host_header.contains(':') {
             host_header.clone()
         } else {
             format!("{}:80", host_header)
         };
         return Some(SniffResult {
-            tunnel_id: tunnel_id,
+            tunnel_id,
             host,
             is_https: false,
+            forward_to,
+            path: request.path.map(|path| path.to_string()),
         });
     }
     None
@@ -91,6 +106,8 @@ fn sniff_tls_sni_safe(buf: &[u8]) -> Result<Option<SniffResult>> {
                         .to_string(),
                     host,
                     is_https: true,
+                    forward_to: None,
+                    path: None,
                 }));
             }
             Ok(None)
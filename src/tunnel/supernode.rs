@@ -1,16 +1,44 @@
-use crate::transport::base::{ServerConfig, TransformServer, TransportStream};
+use crate::transport::base::{
+    ServerConfig, TransformServer, TransportConnection, TransportRecvStream, TransportSendStream,
+};
+use crate::transport::cert::{cert_identity, default_self_signed_sans};
 use crate::transport::quic::QuinnServerEndpoint;
-use crate::tunnel::common::{AUTH_TOKEN_KEY, get_client_id_from_token};
-use crate::tunnel::inbound::{InboundConfig, bind_tcp_inbound};
-use crate::tunnel::outbound::forward_to_tcp;
-use crate::tunnel::packet::{TunnelCommand, TunnelCommandPacket, TunnelMeta};
-use crate::tunnel::session::{TRANSPORT_SESSION_MAP, TransportSession, clear_expired_sessions};
-use serde_json::Value;
+use crate::tunnel::authorizer::{AuthorizeRequest, external_authorizer};
+use crate::tunnel::common::{
+    CLOSE_CONTROL_LOST, CLOSE_KICKED, CLOSE_REPLACED, CLOSE_REVOKED, COMMAND_TIMEOUT,
+    DEVICE_NAME_KEY,
+};
+use crate::tunnel::control::ControlChannel;
+use crate::tunnel::egress::egress_policy;
+use crate::tunnel::error::ProtocolError;
+use crate::tunnel::inbound::{InboundConfig, TlsTermination, bind_tcp_inbound};
+use crate::tunnel::outbound::{ForwardTarget, forward_to_tcp};
+use crate::tunnel::packet::{MetaEncoding, TunnelCommand, TunnelCommandPacket, TunnelMeta};
+use crate::tunnel::payload::{
+    AuthChallenge, AuthRequest, AuthResult, CommandPayload, Kick, Ping, Pong, SessionMetaResult,
+    SessionMetaUpdate,
+};
+use crate::tunnel::session::{
+    SESSION_TIMEOUT, TRANSPORT_SESSION_MAP, TransportSession, add_route, clear_expired_sessions,
+    find_session_id_by_conn, get_session,
+};
+use crate::tunnel::token::{
+    AUTH_EXPORTER_LABEL, TokenGrant, TokenStore, token_id, token_store, verify_auth_proof,
+};
+use crate::tunnel::version::{CAP_CONTROL_STREAM, CAP_SERVER_COMMANDS, ProtocolInfo};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use dashmap::mapref::entry::Entry;
+use dashmap::{DashMap, DashSet};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
 use std::time::Duration;
 use tokio::io::AsyncWriteExt;
-use tokio::io::WriteHalf;
 use tokio::time::Instant;
 
+type Connection = Arc<dyn TransportConnection + Send + Sync + 'static>;
+
 pub async fn start_server(
     quic_bind_addr: String,
     tcp_bind_addr: String,
@@ -21,13 +49,19 @@ pub async fn start_server(
         "[Supernode] Initializing with QUIC={} TCP={} cert={} key={}",
         quic_bind_addr, tcp_bind_addr, cert_path, key_path
     );
+    let client_cert_auth = CLIENT_CERT_AUTH.read().unwrap().clone();
     let config = ServerConfig {
         addr: quic_bind_addr.clone(),
         ssl_cert_path: cert_path.clone(),
         ssl_key_path: key_path.clone(),
+        self_signed_sans: SELF_SIGNED_SANS.read().unwrap().clone(),
+        client_ca_path: client_cert_auth.as_ref().map(|auth| auth.ca_path.clone()),
+        require_client_cert: client_cert_auth
+            .is_some_and(|auth| auth.mode != ClientCertMode::Optional),
     };
     let inbound_config = InboundConfig {
         inbound_addr: tcp_bind_addr.clone(),
+        tls_termination: TLS_TERMINATION.read().unwrap().clone(),
     };
     tokio::spawn(async move {
         loop {
@@ -62,74 +96,78 @@ async fn start_transport(config: ServerConfig) -> anyhow::Result<()> {
     server
         .accept(|conn_box, stream| async move {
             println!("[Supernode] Bi-directional QUIC stream accepted, waiting for command...");
-            let (mut stream_reader, stream_writer) = tokio::io::split(stream);
-            let packet = match TunnelCommandPacket::read_command1(&mut stream_reader).await {
+            let (mut stream_reader, stream_writer) = stream.into_split();
+            let packet = match TunnelCommandPacket::read_from(&mut stream_reader).await {
                 Ok(packet) => packet,
                 Err(err) => {
                     eprintln!("[Supernode] Failed to read command packet: {:?}", err);
-                    return Err(err);
+                    if err.is_recoverable() {
+                        let _ = response_error(stream_writer, &err).await;
+                    }
+                    return Err(err.into());
                 }
             };
             println!("[Supernode] Received command: {:?}", packet.command);
             match packet.command {
                 TunnelCommand::Forward => {
                     println!("[Supernode] Forward command meta: {:?}", packet.meta);
-                    if let Err(err) =
-                        forward_to_tcp(stream_reader, stream_writer, packet, Option::None).await
+                    let Some(session) =
+                        find_session_id_by_conn(conn_box.id()).and_then(|id| get_session(&id))
+                    else {
+                        eprintln!("[Supernode] Forward from unauthenticated connection refused");
+                        return response_error(stream_writer, &ProtocolError::Unauthenticated)
+                            .await;
+                    };
+                    let egress = session.egress.unwrap_or_else(egress_policy);
+                    if let Err(err) = forward_to_tcp(
+                        stream_reader,
+                        stream_writer,
+                        packet,
+                        ForwardTarget::Requested(egress),
+                    )
+                    .await
                     {
                         eprintln!("[Supernode] forward_to_tcp failed: {:?}", err);
                         return Err(err);
                     }
                 }
-                TunnelCommand::Ping => {
-                    let client_id = match packet.meta.get(AUTH_TOKEN_KEY) {
-                        Some(token) => token.as_str().unwrap(),
-                        None => "",
-                    };
-                    println!("[QUIC Server] Ping from client_id: {}", client_id);
-
-                    if let Some(mut entry) = TRANSPORT_SESSION_MAP.get_mut(client_id) {
-                        println!("[QUIC Server] Session found, updating ping_at");
-                        entry.value_mut().ping_at = Instant::now();
-                        if let Err(err) =
-                            response_command(stream_writer, TunnelCommand::Pong, &packet.meta).await
-                        {
-                            eprintln!("[Supernode] Failed to respond Pong: {:?}", err);
-                            return Err(err);
-                        }
-                    } else {
-                        eprintln!(
-                            "[QUIC Server] Session not found for client_id: {}",
-                            client_id
-                        );
-                    }
-                }
                 TunnelCommand::Auth => {
-                    let mut meta = TunnelMeta::from([("result".to_string(), Value::Bool(false))]);
-                    let token: Option<&Value> = packet.meta.get(AUTH_TOKEN_KEY);
-                    if let Some(token) = token {
-                        if let Some(token_str) = token.as_str() {
-                            let client_id = get_client_id_from_token(token_str);
-                            TRANSPORT_SESSION_MAP.insert(
-                                client_id,
-                                TransportSession {
-                                    conn: conn_box.clone(),
-                                    meta: packet.meta.clone(),
-                                    ping_at: Instant::now(),
-                                },
-                            );
-                            meta = TunnelMeta::from([("result".to_string(), Value::Bool(true))]);
+                    let (mut stream_reader, mut stream_writer) = (stream_reader, stream_writer);
+                    let (reply, session_id) =
+                        handle_auth(&conn_box, &packet, &mut stream_reader, &mut stream_writer)
+                            .await;
+                    let control_session = session_id.filter(|id| {
+                        get_session(id)
+                            .is_some_and(|session| session.protocol.supports(CAP_CONTROL_STREAM))
+                    });
+                    match control_session {
+                        Some(session_id) => {
+                            reply.write_to(&mut stream_writer).await?;
+                            stream_writer.flush().await?;
+                            start_control(conn_box, session_id, stream_reader, stream_writer);
+                        }
+                        None => {
+                            if let Err(err) = response_command(stream_writer, reply).await {
+                                eprintln!("[Supernode] Failed to respond AuthResult: {:?}", err);
+                                return Err(err);
+                            }
                         }
                     }
-                    if let Err(err) =
-                        response_command(stream_writer, TunnelCommand::AuthResult, &meta).await
-                    {
-                        eprintln!("[Supernode] Failed to respond AuthResult: {:?}", err);
+                }
+                TunnelCommand::Ping | TunnelCommand::SetSessionMeta => {
+                    let reply = handle_command(&conn_box, &packet);
+                    if let Err(err) = response_command(stream_writer, reply).await {
+                        eprintln!(
+                            "[Supernode] Failed to respond {:?}: {:?}",
+                            packet.command, err
+                        );
                         return Err(err);
                     }
                 }
                 _ => {
                     eprintln!("Unsupported command: {:?}", packet.command);
+                    let err = ProtocolError::UnexpectedCommand(packet.command as u8);
+                    return response_error(stream_writer, &err).await;
                 }
             }
 
@@ -139,16 +177,702 @@ async fn start_transport(config: ServerConfig) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Turns the stream that carried `Auth` into the session's control stream. The
+/// session is dropped and the connection closed as soon as the stream goes away.
+fn start_control(
+    conn: Connection,
+    session_id: String,
+    reader: Box<dyn TransportRecvStream>,
+    writer: Box<dyn TransportSendStream>,
+) {
+    let handler_conn = conn.clone();
+    let control = ControlChannel::spawn(reader, writer, move |packet| {
+        let reply = handle_command(&handler_conn, &packet);
+        async move { Some(reply) }
+    });
+    if let Some(mut session) = TRANSPORT_SESSION_MAP.get_mut(&session_id) {
+        session.control = Some(control.clone());
+    }
+    println!(
+        "[Supernode] Control stream opened for session {}",
+        session_id
+    );
+    tokio::spawn(async move {
+        control.closed().await;
+        println!(
+            "[Supernode] Control stream of session {} closed",
+            session_id
+        );
+        TRANSPORT_SESSION_MAP.remove_if(&session_id, |_, session| session.conn.id() == conn.id());
+        conn.close(CLOSE_CONTROL_LOST, "control stream closed");
+    });
+}
+
+/// Answers a request that may arrive on the control stream or, from legacy
+/// edges, on a stream of its own.
+fn handle_command(conn: &Connection, packet: &TunnelCommandPacket) -> TunnelCommandPacket {
+    let reply = match packet.command {
+        TunnelCommand::Ping => handle_ping(conn, packet),
+        TunnelCommand::SetSessionMeta => handle_set_session_meta(conn, packet),
+        _ => {
+            eprintln!(
+                "[Supernode] Unsupported control command: {:?}",
+                packet.command
+            );
+            Err(ProtocolError::UnexpectedCommand(packet.command as u8))
+        }
+    };
+    reply.unwrap_or_else(|err| TunnelCommandPacket::error(&err))
+}
+
+fn handle_ping(
+    conn: &Connection,
+    packet: &TunnelCommandPacket,
+) -> Result<TunnelCommandPacket, ProtocolError> {
+    let ping = packet.payload::<Ping>()?;
+    // The token some edges still put in `Ping` is ignored: it is not the session id.
+    let client_id = find_session_id_by_conn(conn.id()).unwrap_or_default();
+    println!("[QUIC Server] Ping from client_id: {}", client_id);
+
+    match TRANSPORT_SESSION_MAP.get_mut(&client_id) {
+        Some(mut entry) => {
+            entry.value_mut().ping_at = Instant::now();
+            Ok(
+                TunnelCommandPacket::from_payload(&Pong::from(&ping))
+                    .with_encoding(packet.encoding),
+            )
+        }
+        None => {
+            eprintln!(
+                "[QUIC Server] Session not found for client_id: {}",
+                client_id
+            );
+            Err(ProtocolError::Unauthenticated)
+        }
+    }
+}
+
+/// Sends a one-way command to an edge over its control stream.
+pub fn push_command<P: CommandPayload>(session_id: &str, payload: &P) -> anyhow::Result<()> {
+    let session =
+        get_session(session_id).ok_or(anyhow::anyhow!("Session {} not found", session_id))?;
+    let control = session
+        .control
+        .filter(|_| session.protocol.supports(CAP_SERVER_COMMANDS))
+        .ok_or(anyhow::anyhow!(
+            "Session {} does not accept server commands",
+            session_id
+        ))?;
+    control.send(session.protocol.packet(payload)?)
+}
+
+/// Disconnects an edge and refuses its `Auth` until `retry_after` has passed.
+/// Edges too old to understand `Kick` are disconnected all the same. The ban is
+/// on the client id: the members of a group cannot be told apart when they
+/// reconnect, so kicking one keeps all of them from authenticating again, while
+/// those still connected stay connected.
+pub fn kick_session(session_id: &str, kick: &Kick) -> anyhow::Result<()> {
+    let (_, session) = TRANSPORT_SESSION_MAP
+        .remove(session_id)
+        .ok_or(anyhow::anyhow!("Session {} not found", session_id))?;
+    KICKED_UNTIL.insert(
+        session.client_id.clone(),
+        Instant::now() + Duration::from_secs(kick.retry_after),
+    );
+    let reason = kick.reason.clone().unwrap_or("kicked".to_string());
+    println!("[Supernode] Kicking session {}: {}", session_id, reason);
+    let notified = match (
+        &session.control,
+        session.protocol.supports(CAP_SERVER_COMMANDS),
+    ) {
+        (Some(control), true) => session
+            .protocol
+            .packet(kick)
+            .map_err(anyhow::Error::from)
+            .and_then(|packet| control.send(packet))
+            .is_ok(),
+        _ => false,
+    };
+    tokio::spawn(async move {
+        // Let the edge close the connection itself so it sees the reason.
+        if notified {
+            tokio::time::sleep(KICK_GRACE).await;
+        }
+        session.conn.close(CLOSE_KICKED, &reason);
+    });
+    Ok(())
+}
+
+/// Time a kicked edge gets to read `Kick` before the connection is closed on it.
+const KICK_GRACE: Duration = Duration::from_secs(2);
+
+static KICKED_UNTIL: LazyLock<DashMap<String, Instant>> = LazyLock::new(DashMap::new);
+
+/// Token ids and client ids revoked through the admin API. Their `Auth` is
+/// refused until the revocation is lifted or the supernode restarts.
+static REVOKED_TOKEN_IDS: LazyLock<DashSet<String>> = LazyLock::new(DashSet::new);
+static REVOKED_CLIENT_IDS: LazyLock<DashSet<String>> = LazyLock::new(DashSet::new);
+
+/// Refuses the token and/or client id from now on and disconnects their live
+/// sessions. Returns the ids of the disconnected sessions.
+pub fn revoke(token_id: Option<&str>, client_id: Option<&str>, reason: &str) -> Vec<String> {
+    if let Some(token_id) = token_id {
+        REVOKED_TOKEN_IDS.insert(token_id.to_string());
+    }
+    if let Some(client_id) = client_id {
+        REVOKED_CLIENT_IDS.insert(client_id.to_string());
+    }
+    close_revoked_sessions(reason)
+}
+
+/// Lifts a revocation made with `revoke`.
+pub fn unrevoke(token_id: Option<&str>, client_id: Option<&str>) {
+    if let Some(token_id) = token_id {
+        REVOKED_TOKEN_IDS.remove(token_id);
+    }
+    if let Some(client_id) = client_id {
+        REVOKED_CLIENT_IDS.remove(client_id);
+    }
+}
+
+pub fn revoked_ids() -> (Vec<String>, Vec<String>) {
+    (
+        REVOKED_TOKEN_IDS.iter().map(|id| id.clone()).collect(),
+        REVOKED_CLIENT_IDS.iter().map(|id| id.clone()).collect(),
+    )
+}
+
+fn is_revoked(client_id: &str, token_id: Option<&str>) -> bool {
+    REVOKED_CLIENT_IDS.contains(client_id)
+        || token_id.is_some_and(|token_id| REVOKED_TOKEN_IDS.contains(token_id))
+}
+
+fn close_revoked_sessions(reason: &str) -> Vec<String> {
+    close_sessions_where(
+        |_, session| is_revoked(&session.client_id, session.token_id.as_deref()),
+        reason,
+    )
+}
+
+/// Disconnects the sessions of tokens that were removed from the token store,
+/// without refusing them should they come back.
+pub fn close_sessions_of_tokens(token_ids: &[String], reason: &str) -> Vec<String> {
+    close_sessions_where(
+        |_, session| {
+            session
+                .token_id
+                .as_ref()
+                .is_some_and(|token_id| token_ids.contains(token_id))
+        },
+        reason,
+    )
+}
+
+/// Removes the matching sessions and closes their connections with
+/// `CLOSE_REVOKED`, which tells the edges not to reconnect.
+fn close_sessions_where(
+    matches: impl Fn(&str, &TransportSession) -> bool,
+    reason: &str,
+) -> Vec<String> {
+    let ids: Vec<String> = TRANSPORT_SESSION_MAP
+        .iter()
+        .filter(|session| matches(session.key(), session.value()))
+        .map(|session| session.key().clone())
+        .collect();
+    for id in &ids {
+        if let Some((_, session)) = TRANSPORT_SESSION_MAP.remove(id) {
+            println!("[Supernode] Revoking session {}: {}", id, reason);
+            session.conn.close(CLOSE_REVOKED, reason);
+        }
+    }
+    ids
+}
+
+static LEGACY_AUTH: AtomicBool = AtomicBool::new(true);
+
+/// Whether edges may still send their token in `Auth` instead of answering an
+/// `AuthChallenge`. Enabled by default so older edges can connect.
+pub fn set_legacy_auth(enabled: bool) {
+    LEGACY_AUTH.store(enabled, Ordering::Relaxed);
+}
+
+/// Registers the session on success. Returns the reply and the session id.
+async fn handle_auth(
+    conn: &Connection,
+    packet: &TunnelCommandPacket,
+    reader: &mut Box<dyn TransportRecvStream>,
+    writer: &mut Box<dyn TransportSendStream>,
+) -> (TunnelCommandPacket, Option<String>) {
+    let (result, session_id) = match register_session(conn, packet, reader, writer).await {
+        Ok((protocol, client_id)) => (AuthResult::accepted(&protocol), Some(client_id)),
+        Err(err) => {
+            eprintln!("[Supernode] Auth rejected: {}", err);
+            (AuthResult::rejected(err.to_string()), None)
+        }
+    };
+    let reply = TunnelCommandPacket::from_payload(&result).with_encoding(packet.encoding);
+    (reply, session_id)
+}
+
+async fn register_session(
+    conn: &Connection,
+    packet: &TunnelCommandPacket,
+    reader: &mut Box<dyn TransportRecvStream>,
+    writer: &mut Box<dyn TransportSendStream>,
+) -> anyhow::Result<(ProtocolInfo, String)> {
+    let request = packet.payload::<AuthRequest>()?;
+    let protocol = request.protocol()?;
+    println!(
+        "[Supernode] Negotiated protocol v{} with capabilities {:?}",
+        protocol.version, protocol.capabilities
+    );
+    let token_grant = if request.token_id.is_some() || !request.token.is_empty() {
+        Some(authorize_token(conn, &request, packet.encoding, reader, writer).await?)
+    } else {
+        None
+    };
+    let mode = CLIENT_CERT_AUTH
+        .read()
+        .unwrap()
+        .as_ref()
+        .map(|auth| auth.mode);
+    let grant = combine_grants(mode, certificate_grant(conn)?, token_grant)?;
+    let client_id = grant.client_id.clone();
+    let token_id = match &request.token_id {
+        Some(token_id) => Some(token_id.clone()),
+        None if !request.token.is_empty() => Some(token_id(&request.token)),
+        None => None,
+    };
+    if is_revoked(&client_id, token_id.as_deref()) {
+        return Err(anyhow::anyhow!("revoked"));
+    }
+    KICKED_UNTIL.remove_if(&client_id, |_, until| *until <= Instant::now());
+    if KICKED_UNTIL.contains_key(&client_id) {
+        return Err(anyhow::anyhow!("kicked, retry later"));
+    }
+    let mut meta = request.extra.clone();
+    if let Some(hostname) = &request.hostname {
+        meta.insert(DEVICE_NAME_KEY.to_string(), hostname.clone().into());
+    }
+    let tunnels = grant.tunnel_names();
+    let session_id = insert_session(TransportSession {
+        conn: conn.clone(),
+        client_id: client_id.clone(),
+        meta,
+        ping_at: Instant::now(),
+        protocol: protocol.clone(),
+        token_id,
+        control: None,
+        tunnels: tunnels.clone(),
+        limits: grant.limits,
+        egress: grant.egress,
+        active_streams: Default::default(),
+    })?;
+    for tunnel in &tunnels {
+        add_route(tunnel, &session_id);
+    }
+    println!(
+        "[Supernode] Client {} authenticated as session {} for tunnels {:?}",
+        client_id, session_id, tunnels
+    );
+    Ok((protocol, session_id))
+}
+
+/// What happens when an edge authenticates with a client id that already has a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateClientPolicy {
+    /// The newcomer is refused while the existing session is alive.
+    Reject,
+    /// The newcomer takes over and the existing connection is closed.
+    #[default]
+    Replace,
+    /// Both stay connected and share the tunnels' connections between them.
+    Group,
+}
+
+impl FromStr for DuplicateClientPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(DuplicateClientPolicy::Reject),
+            "replace" => Ok(DuplicateClientPolicy::Replace),
+            "group" => Ok(DuplicateClientPolicy::Group),
+            _ => Err(anyhow::anyhow!(
+                "expected reject, replace or group, got {}",
+                s
+            )),
+        }
+    }
+}
+
+static DUPLICATE_CLIENT_POLICY: LazyLock<std::sync::RwLock<DuplicateClientPolicy>> =
+    LazyLock::new(Default::default);
+
+pub fn set_duplicate_client_policy(policy: DuplicateClientPolicy) {
+    *DUPLICATE_CLIENT_POLICY.write().unwrap() = policy;
+}
+
+/// Stores a new session under its client id, applying the duplicate client
+/// policy when that id is taken. Returns the session id.
+fn insert_session(session: TransportSession) -> anyhow::Result<String> {
+    insert_session_with(session, *DUPLICATE_CLIENT_POLICY.read().unwrap())
+}
+
+fn insert_session_with(
+    session: TransportSession,
+    policy: DuplicateClientPolicy,
+) -> anyhow::Result<String> {
+    let client_id = session.client_id.clone();
+    let mut existing = match TRANSPORT_SESSION_MAP.entry(client_id.clone()) {
+        Entry::Vacant(entry) => {
+            entry.insert(session);
+            return Ok(client_id);
+        }
+        Entry::Occupied(existing) => existing,
+    };
+    // A session that stopped pinging is as good as gone, whatever the policy.
+    let stale = existing.get().conn.id() == session.conn.id()
+        || existing.get().ping_at.elapsed() > SESSION_TIMEOUT;
+    match policy {
+        _ if stale => {
+            existing.insert(session);
+            Ok(client_id)
+        }
+        DuplicateClientPolicy::Reject => Err(anyhow::anyhow!(
+            "client id {} is already connected from {}",
+            client_id,
+            existing.get().conn.remote_addr()
+        )),
+        DuplicateClientPolicy::Replace => {
+            let reason = format!(
+                "replaced by another connection of {} from {}",
+                client_id,
+                session.conn.remote_addr()
+            );
+            let replaced = existing.insert(session);
+            println!("[Supernode] Session {} {}", client_id, reason);
+            replaced.conn.close(CLOSE_REPLACED, &reason);
+            Ok(client_id)
+        }
+        DuplicateClientPolicy::Group => {
+            drop(existing);
+            // Further members are `client_id#2`, `client_id#3`, ...
+            for n in 2.. {
+                let session_id = format!("{}#{}", client_id, n);
+                if let Entry::Vacant(entry) = TRANSPORT_SESSION_MAP.entry(session_id.clone()) {
+                    entry.insert(session);
+                    return Ok(session_id);
+                }
+            }
+            unreachable!()
+        }
+    }
+}
+
+static TLS_TERMINATION: LazyLock<std::sync::RwLock<Option<TlsTermination>>> =
+    LazyLock::new(Default::default);
+
+/// Terminates public TLS for some tunnels. Must be called before `start_server`.
+pub fn set_tls_termination(termination: Option<TlsTermination>) {
+    *TLS_TERMINATION.write().unwrap() = termination;
+}
+
+static SELF_SIGNED_SANS: LazyLock<std::sync::RwLock<Vec<String>>> =
+    LazyLock::new(|| std::sync::RwLock::new(default_self_signed_sans()));
+
+/// Names of the certificate generated on first start, when neither the
+/// certificate nor the key file exists. Must be called before `start_server`.
+pub fn set_self_signed_sans(sans: Vec<String>) {
+    *SELF_SIGNED_SANS.write().unwrap() = sans;
+}
+
+/// How edges are authenticated once the supernode asks for client certificates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ClientCertMode {
+    /// A certificate or a token is enough; when both are given they must agree.
+    #[default]
+    Optional,
+    /// Every edge needs a certificate, which identifies it on its own.
+    Required,
+    /// Every edge needs a certificate and a token for the same client id.
+    WithToken,
+}
+
+impl FromStr for ClientCertMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "optional" => Ok(ClientCertMode::Optional),
+            "required" => Ok(ClientCertMode::Required),
+            "with-token" => Ok(ClientCertMode::WithToken),
+            _ => Err(anyhow::anyhow!(
+                "expected optional, required or with-token, got {}",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientCertAuth {
+    /// PEM file of the CAs that issue edge certificates.
+    pub ca_path: String,
+    pub mode: ClientCertMode,
+}
+
+static CLIENT_CERT_AUTH: LazyLock<std::sync::RwLock<Option<ClientCertAuth>>> =
+    LazyLock::new(Default::default);
+
+/// Lets edges authenticate with client certificates. Must be called before `start_server`.
+pub fn set_client_cert_auth(auth: Option<ClientCertAuth>) {
+    *CLIENT_CERT_AUTH.write().unwrap() = auth;
+}
+
+/// The identity of an edge's client certificate: the subject common name is
+/// the client id (the first DNS name when there is none) and the DNS names are
+/// its tunnels.
+fn certificate_grant(conn: &Connection) -> anyhow::Result<Option<TokenGrant>> {
+    let Some(cert) = conn
+        .peer_certificates()
+        .and_then(|certs| certs.into_iter().next())
+    else {
+        return Ok(None);
+    };
+    let identity = cert_identity(&cert)?;
+    let client_id = identity
+        .common_name
+        .or_else(|| identity.dns_names.first().cloned())
+        .ok_or(anyhow::anyhow!(
+            "client certificate has neither a common name nor a DNS name"
+        ))?;
+    Ok(Some(TokenGrant {
+        client_id,
+        tunnels: identity.dns_names,
+        ..Default::default()
+    }))
+}
+
+/// Decides an `Auth` from its certificate and token grants. `mode` is `None`
+/// when the supernode does not ask for client certificates.
+fn combine_grants(
+    mode: Option<ClientCertMode>,
+    cert: Option<TokenGrant>,
+    token: Option<TokenGrant>,
+) -> anyhow::Result<TokenGrant> {
+    match (cert, token) {
+        (Some(cert), Some(token)) if cert.client_id != token.client_id => Err(anyhow::anyhow!(
+            "token of {} does not match the client certificate of {}",
+            token.client_id,
+            cert.client_id
+        )),
+        // The token's grant wins so it can narrow tunnels and set limits.
+        (Some(_), Some(token)) => Ok(token),
+        (Some(_), None) if mode == Some(ClientCertMode::WithToken) => Err(anyhow::anyhow!(
+            "a token is required in addition to the client certificate"
+        )),
+        (Some(cert), None) => Ok(cert),
+        (None, Some(_)) if mode.is_some_and(|mode| mode != ClientCertMode::Optional) => {
+            Err(anyhow::anyhow!("client certificate required"))
+        }
+        (None, Some(token)) => Ok(token),
+        (None, None) => Err(anyhow::anyhow!("unknown token")),
+    }
+}
+
+/// Checks the edge's token against the token store and, for tokens the store
+/// does not know, the external authorizer.
+async fn authorize_token(
+    conn: &Connection,
+    request: &AuthRequest,
+    encoding: MetaEncoding,
+    reader: &mut Box<dyn TransportRecvStream>,
+    writer: &mut Box<dyn TransportSendStream>,
+) -> anyhow::Result<TokenGrant> {
+    let store = token_store();
+    let proof = match &request.token_id {
+        Some(_) if store.accepts_any() => {
+            return Err(anyhow::anyhow!(
+                "no token registry to check the proof against, connect with --legacy-auth"
+            ));
+        }
+        Some(token_id) => {
+            let proof = challenge(conn, encoding, reader, writer).await?;
+            if let Some(grant) = authorize_proof(store.as_ref(), token_id, &proof).await? {
+                return Ok(grant);
+            }
+            Some(proof)
+        }
+        None if LEGACY_AUTH.load(Ordering::Relaxed) => {
+            if let Some(grant) = store.authorize(&request.token).await? {
+                return Ok(grant);
+            }
+            None
+        }
+        None => return Err(anyhow::anyhow!("legacy token auth is disabled")),
+    };
+    let authorizer = external_authorizer().ok_or(anyhow::anyhow!("unknown token"))?;
+    let response = authorizer
+        .authorize(&AuthorizeRequest {
+            token: proof.is_none().then(|| request.token.clone()),
+            token_id: request
+                .token_id
+                .clone()
+                .unwrap_or_else(|| token_id(&request.token)),
+            remote_addr: conn.remote_addr().to_string(),
+            hostname: request.hostname.clone(),
+            meta: request.extra.clone(),
+        })
+        .await?;
+    if let Some(proof) = &proof
+        && response.allow
+        && !response
+            .token
+            .as_deref()
+            .is_some_and(|token| proof.verify(token))
+    {
+        return Err(anyhow::anyhow!("unknown token"));
+    }
+    response.grant()
+}
+
+/// The grant of an edge that proved it holds the token of `token_id`, or `None`
+/// when `store` does not know the id or the proof does not match.
+async fn authorize_proof(
+    store: &dyn TokenStore,
+    token_id: &str,
+    proof: &AuthProof,
+) -> anyhow::Result<Option<TokenGrant>> {
+    // Unknown ids are still challenged, so probing does not reveal which tokens exist.
+    match store.find_token(token_id).await {
+        Some(token) if proof.verify(&token) => store.authorize(&token).await,
+        _ => Ok(None),
+    }
+}
+
+/// An edge's answer to an `AuthChallenge`.
+struct AuthProof {
+    nonce: [u8; 32],
+    exporter: [u8; 32],
+    proof: Vec<u8>,
+}
+
+impl AuthProof {
+    fn verify(&self, token: &str) -> bool {
+        verify_auth_proof(token, &self.nonce, &self.exporter, &self.proof)
+    }
+}
+
+/// Sends an `AuthChallenge` and reads the proof the edge answers with.
+async fn challenge(
+    conn: &Connection,
+    encoding: MetaEncoding,
+    reader: &mut Box<dyn TransportRecvStream>,
+    writer: &mut Box<dyn TransportSendStream>,
+) -> anyhow::Result<AuthProof> {
+    let mut nonce = [0u8; 32];
+    aws_lc_rs::rand::fill(&mut nonce).map_err(|_| anyhow::anyhow!("no randomness"))?;
+    let challenge = AuthChallenge {
+        nonce: URL_SAFE_NO_PAD.encode(nonce),
+        ..Default::default()
+    };
+    TunnelCommandPacket::from_payload(&challenge)
+        .with_encoding(encoding)
+        .write_to(writer)
+        .await?;
+    writer.flush().await?;
+    let response = tokio::time::timeout(COMMAND_TIMEOUT, TunnelCommandPacket::read_from(reader))
+        .await
+        .map_err(|_| anyhow::anyhow!("auth proof timeout"))??;
+    let proof = response
+        .payload::<AuthRequest>()?
+        .proof
+        .and_then(|proof| URL_SAFE_NO_PAD.decode(proof).ok())
+        .ok_or(anyhow::anyhow!("missing auth proof"))?;
+    let mut exporter = [0u8; 32];
+    conn.export_keying_material(&mut exporter, AUTH_EXPORTER_LABEL, &[])?;
+    Ok(AuthProof {
+        nonce,
+        exporter,
+        proof,
+    })
+}
+
+/// Limits on a session's meta once a `SetSessionMeta` is merged in, so an edge
+/// cannot grow it without bound.
+const MAX_SESSION_META_LEN: usize = 16 * 1024;
+const MAX_SESSION_META_KEYS: usize = 64;
+const MAX_SESSION_META_TAGS: usize = 32;
+
+fn handle_set_session_meta(
+    conn: &Connection,
+    packet: &TunnelCommandPacket,
+) -> Result<TunnelCommandPacket, ProtocolError> {
+    let update = packet.payload::<SessionMetaUpdate>()?;
+    match find_session_id_by_conn(conn.id()).and_then(|id| TRANSPORT_SESSION_MAP.get_mut(&id)) {
+        Some(mut session) => {
+            let result = update_session_meta(&mut session.meta, &update);
+            match &result.reason {
+                None => println!(
+                    "[Supernode] Session {} meta updated: {:?}",
+                    session.key(),
+                    session.meta
+                ),
+                Some(reason) => eprintln!(
+                    "[Supernode] Session {} meta update refused: {}",
+                    session.key(),
+                    reason
+                ),
+            }
+            session.protocol.packet(&result)
+        }
+        None => {
+            let result = SessionMetaResult {
+                ok: false,
+                reason: Some("connection is not authenticated".to_string()),
+                ..Default::default()
+            };
+            Ok(TunnelCommandPacket::from_payload(&result).with_encoding(packet.encoding))
+        }
+    }
+}
+
+/// Merges `update` into `meta`, leaving it unchanged when the result would be
+/// over the limits.
+fn update_session_meta(meta: &mut TunnelMeta, update: &SessionMetaUpdate) -> SessionMetaResult {
+    let mut merged = meta.clone();
+    update.apply(&mut merged);
+    let tags = update.tags.as_ref().map_or(0, Vec::len);
+    let len = serde_json::to_vec(&merged).map_or(usize::MAX, |bytes| bytes.len());
+    let reason = if merged.len() > MAX_SESSION_META_KEYS {
+        Some(format!(
+            "session meta has more than {} keys",
+            MAX_SESSION_META_KEYS
+        ))
+    } else if tags > MAX_SESSION_META_TAGS {
+        Some(format!("more than {} tags", MAX_SESSION_META_TAGS))
+    } else if len > MAX_SESSION_META_LEN {
+        Some(format!(
+            "session meta is larger than {} bytes",
+            MAX_SESSION_META_LEN
+        ))
+    } else {
+        *meta = merged;
+        None
+    };
+    SessionMetaResult {
+        ok: reason.is_none(),
+        reason,
+        meta: meta.clone(),
+    }
+}
+
 pub async fn response_command(
-    mut stream: WriteHalf<Box<dyn TransportStream>>,
-    command: TunnelCommand,
-    meta: &TunnelMeta,
+    mut stream: Box<dyn TransportSendStream>,
+    command_packet: TunnelCommandPacket,
 ) -> Result<TunnelCommandPacket, anyhow::Error> {
-    let command_packet = TunnelCommandPacket::new(command, meta);
-    let command_bytes = command_packet.to_bytes();
-    if let Err(e) = stream.write_all(&command_bytes).await {
-        return Err(anyhow::anyhow!(e));
-    }
+    command_packet.write_to(&mut stream).await?;
     if let Err(e) = stream.flush().await {
         return Err(anyhow::anyhow!(e));
     }
@@ -158,3 +882,244 @@ pub async fn response_command(
     println!("response_command: {:?}", command_packet);
     Ok(command_packet)
 }
+
+/// Replies with an `Error` command and closes the stream. Always returns `Ok`
+/// so callers can `return response_error(..).await` from a stream handler.
+pub async fn response_error(
+    mut stream: Box<dyn TransportSendStream>,
+    error: &ProtocolError,
+) -> Result<(), anyhow::Error> {
+    let packet = TunnelCommandPacket::error(error);
+    if let Err(e) = packet.write_to(&mut stream).await {
+        eprintln!("[ERROR] Failed to send error reply: {:?}", e);
+    }
+    let _ = stream.shutdown().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::session::tests::fake_session;
+    use crate::tunnel::token::{AllowAnyToken, FileTokenStore, auth_proof};
+
+    fn proof_for(token: &str) -> AuthProof {
+        let (nonce, exporter) = ([1u8; 32], [2u8; 32]);
+        AuthProof {
+            nonce,
+            exporter,
+            proof: auth_proof(token, &nonce, &exporter),
+        }
+    }
+
+    #[test]
+    fn duplicate_client_policies() {
+        // Each case uses its own client id, the session map is shared with other tests.
+        let (first, _) = fake_session("dup-reject");
+        assert_eq!(
+            insert_session_with(first, DuplicateClientPolicy::Reject).unwrap(),
+            "dup-reject"
+        );
+        let (second, _) = fake_session("dup-reject");
+        let err = insert_session_with(second, DuplicateClientPolicy::Reject).unwrap_err();
+        assert!(err.to_string().contains("already connected"), "{}", err);
+
+        let (first, first_conn) = fake_session("dup-replace");
+        insert_session_with(first, DuplicateClientPolicy::Replace).unwrap();
+        let (second, second_conn) = fake_session("dup-replace");
+        insert_session_with(second, DuplicateClientPolicy::Replace).unwrap();
+        let (code, _) = first_conn.closed.lock().unwrap().clone().unwrap();
+        assert_eq!(code, CLOSE_REPLACED);
+        assert_eq!(
+            TRANSPORT_SESSION_MAP.get("dup-replace").unwrap().conn.id(),
+            second_conn.id()
+        );
+
+        let ids: Vec<String> = (0..3)
+            .map(|_| {
+                insert_session_with(fake_session("dup-group").0, DuplicateClientPolicy::Group)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(ids, ["dup-group", "dup-group#2", "dup-group#3"]);
+
+        // A session that stopped pinging is replaced even under `reject`.
+        let (mut stale, stale_conn) = fake_session("dup-stale");
+        stale.ping_at -= SESSION_TIMEOUT + Duration::from_secs(1);
+        insert_session_with(stale, DuplicateClientPolicy::Reject).unwrap();
+        let (fresh, _) = fake_session("dup-stale");
+        insert_session_with(fresh, DuplicateClientPolicy::Reject).unwrap();
+        assert!(stale_conn.closed.lock().unwrap().is_none());
+        assert_ne!(
+            TRANSPORT_SESSION_MAP.get("dup-stale").unwrap().conn.id(),
+            stale_conn.id()
+        );
+
+        for id in [
+            "dup-reject",
+            "dup-replace",
+            "dup-group",
+            "dup-group#2",
+            "dup-group#3",
+            "dup-stale",
+        ] {
+            TRANSPORT_SESSION_MAP.remove(id);
+        }
+    }
+
+    #[test]
+    fn certificate_and_token_decision_table() {
+        use ClientCertMode::*;
+        let grant = |client_id: &str| {
+            Some(TokenGrant {
+                client_id: client_id.to_string(),
+                ..Default::default()
+            })
+        };
+        // (mode, certificate, token, client id or error)
+        type Case = (
+            Option<ClientCertMode>,
+            Option<&'static str>,
+            Option<&'static str>,
+            Result<&'static str, &'static str>,
+        );
+        let cases: &[Case] = &[
+            (None, None, Some("laptop"), Ok("laptop")),
+            (None, None, None, Err("unknown token")),
+            (Some(Optional), Some("laptop"), None, Ok("laptop")),
+            (Some(Optional), None, Some("laptop"), Ok("laptop")),
+            (Some(Optional), Some("laptop"), Some("laptop"), Ok("laptop")),
+            (
+                Some(Optional),
+                Some("laptop"),
+                Some("phone"),
+                Err("does not match"),
+            ),
+            (Some(Required), Some("laptop"), None, Ok("laptop")),
+            (
+                Some(Required),
+                None,
+                Some("laptop"),
+                Err("client certificate required"),
+            ),
+            (
+                Some(WithToken),
+                Some("laptop"),
+                Some("laptop"),
+                Ok("laptop"),
+            ),
+            (
+                Some(WithToken),
+                Some("laptop"),
+                None,
+                Err("token is required"),
+            ),
+            (
+                Some(WithToken),
+                None,
+                Some("laptop"),
+                Err("client certificate required"),
+            ),
+            (
+                Some(WithToken),
+                Some("laptop"),
+                Some("phone"),
+                Err("does not match"),
+            ),
+        ];
+        for (mode, cert, token, expected) in cases {
+            let result = combine_grants(*mode, cert.and_then(grant), token.and_then(grant));
+            let case = format!("{:?} cert={:?} token={:?}", mode, cert, token);
+            match (result, expected) {
+                (Ok(grant), Ok(client_id)) => assert_eq!(grant.client_id, *client_id, "{}", case),
+                (Err(e), Err(reason)) => {
+                    assert!(e.to_string().contains(reason), "{}: {}", case, e)
+                }
+                (result, _) => panic!("{}: {:?}", case, result.map(|g| g.client_id)),
+            }
+        }
+        // With both, the token's grant is used so it can narrow the tunnels.
+        let token = TokenGrant {
+            client_id: "laptop".to_string(),
+            tunnels: vec!["web".to_string()],
+            ..Default::default()
+        };
+        let combined = combine_grants(Some(Optional), grant("laptop"), Some(token.clone()));
+        assert_eq!(combined.unwrap(), token);
+    }
+
+    #[test]
+    fn oversized_session_meta_is_refused() {
+        let mut meta = TunnelMeta::new();
+        let update = SessionMetaUpdate {
+            display_name: Some("nas".to_string()),
+            ..Default::default()
+        };
+        assert!(update_session_meta(&mut meta, &update).ok);
+
+        let mut big = SessionMetaUpdate::default();
+        big.extra
+            .insert("blob".to_string(), "x".repeat(MAX_SESSION_META_LEN).into());
+        let result = update_session_meta(&mut meta, &big);
+        assert!(!result.ok);
+        assert_eq!(result.meta, meta);
+        assert!(!meta.contains_key("blob"));
+
+        let mut many = SessionMetaUpdate::default();
+        for i in 0..MAX_SESSION_META_KEYS {
+            many.extra.insert(format!("k{}", i), i.into());
+        }
+        assert!(!update_session_meta(&mut meta, &many).ok);
+        let tags = SessionMetaUpdate {
+            tags: Some(vec!["t".to_string(); MAX_SESSION_META_TAGS + 1]),
+            ..Default::default()
+        };
+        assert!(!update_session_meta(&mut meta, &tags).ok);
+        assert_eq!(meta.len(), 1);
+
+        // Replies go through the negotiated size limit instead of panicking.
+        let (mut session, _) = fake_session("meta-test");
+        session.protocol.max_data_len = 8;
+        assert!(matches!(
+            session.protocol.packet(&result),
+            Err(ProtocolError::DataTooLarge(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn challenge_is_refused_by_the_default_store() {
+        // The default store has no token to check a proof with, so it must not
+        // hand out the tunnel of whoever claims a token id.
+        assert!(AllowAnyToken.accepts_any());
+        let id = token_id("my-secret-token");
+        let grant = authorize_proof(&AllowAnyToken, &id, &proof_for("my-secret-token"))
+            .await
+            .unwrap();
+        assert!(grant.is_none());
+    }
+
+    #[tokio::test]
+    async fn challenge_needs_a_proof_made_with_the_token() {
+        let path = std::env::temp_dir().join(format!("proof-tokens-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"tokens": [{"token": "s3cret", "client_id": "laptop"}]}"#,
+        )
+        .unwrap();
+        let store = FileTokenStore::load(&path).unwrap();
+        let id = token_id("s3cret");
+        let grant = authorize_proof(store.as_ref(), &id, &proof_for("s3cret"))
+            .await
+            .unwrap();
+        assert_eq!(grant.unwrap().client_id, "laptop");
+        let forged = authorize_proof(store.as_ref(), &id, &proof_for("guess"))
+            .await
+            .unwrap();
+        assert!(forged.is_none());
+        let unknown = authorize_proof(store.as_ref(), &token_id("other"), &proof_for("other"))
+            .await
+            .unwrap();
+        assert!(unknown.is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,473 @@
+use aws_lc_rs::{digest, hmac};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::tunnel::common::{get_client_id_from_token, unix_millis};
+use crate::tunnel::egress::EgressPolicy;
+use crate::tunnel::supernode::close_sessions_of_tokens;
+
+/// Per-session limits a token can carry.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenLimits {
+    /// Concurrent public connections forwarded to the session.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_streams: Option<u32>,
+}
+
+impl TokenLimits {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// What an accepted token entitles the edge to.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenGrant {
+    pub client_id: String,
+    /// Tunnel names the edge may serve. Empty means just its `client_id`.
+    #[serde(default)]
+    pub tunnels: Vec<String>,
+    #[serde(default, skip_serializing_if = "TokenLimits::is_empty")]
+    pub limits: TokenLimits,
+    /// Replaces the supernode's egress policy for this edge's `Forward`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub egress: Option<EgressPolicy>,
+}
+
+impl TokenGrant {
+    pub fn tunnel_names(&self) -> Vec<String> {
+        if self.tunnels.is_empty() {
+            vec![self.client_id.clone()]
+        } else {
+            self.tunnels.clone()
+        }
+    }
+}
+
+#[async_trait::async_trait]
+pub trait TokenStore: Send + Sync {
+    /// Returns the grant of `token`, or `None` when this store does not know it.
+    /// An error means the token is known but refused; it is sent to the edge as the reason.
+    async fn authorize(&self, token: &str) -> anyhow::Result<Option<TokenGrant>>;
+
+    /// Returns the token whose `token_id` is `token_id`, so an `Auth` proof made
+    /// with it can be checked. Stores that cannot recover tokens return `None`.
+    async fn find_token(&self, _token_id: &str) -> Option<String> {
+        None
+    }
+
+    /// Whether this store accepts any token without knowing it. Such a store
+    /// cannot check an `Auth` proof, so challenge auth is refused.
+    fn accepts_any(&self) -> bool {
+        false
+    }
+}
+
+/// Names a token without revealing it. Signed tokens are named by everything
+/// but their signature, other tokens by their SHA-256.
+pub fn token_id(token: &str) -> String {
+    match token.rsplit_once('.') {
+        Some((signing_input, _)) if token.split('.').count() == 3 => signing_input.to_string(),
+        _ => format!(
+            "sha256:{}",
+            URL_SAFE_NO_PAD.encode(digest::digest(&digest::SHA256, token.as_bytes()))
+        ),
+    }
+}
+
+/// TLS exporter label of the keying material an `Auth` proof is bound to.
+pub const AUTH_EXPORTER_LABEL: &[u8] = b"EXPORTER-ping-tunnel-auth";
+
+/// HMAC-SHA256 keyed with the token over the challenge nonce and the TLS
+/// exporter of the connection, so the proof is useless on any other connection.
+pub fn auth_proof(token: &str, nonce: &[u8], exporter: &[u8]) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, token.as_bytes());
+    let mut context = hmac::Context::with_key(&key);
+    context.update(nonce);
+    context.update(exporter);
+    context.sign().as_ref().to_vec()
+}
+
+pub fn verify_auth_proof(token: &str, nonce: &[u8], exporter: &[u8], proof: &[u8]) -> bool {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, token.as_bytes());
+    let message = [nonce, exporter].concat();
+    hmac::verify(&key, &message, proof).is_ok()
+}
+
+/// Accepts every token and uses it as the client id, like supernodes before the
+/// token registry did. Edges using challenge auth are refused, as there is no
+/// token to check their proof with. Only meant for local development.
+pub struct AllowAnyToken;
+
+#[async_trait::async_trait]
+impl TokenStore for AllowAnyToken {
+    async fn authorize(&self, token: &str) -> anyhow::Result<Option<TokenGrant>> {
+        if token.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(TokenGrant {
+            client_id: get_client_id_from_token(token),
+            ..Default::default()
+        }))
+    }
+
+    fn accepts_any(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenFile {
+    tokens: Vec<TokenFileEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenFileEntry {
+    token: String,
+    #[serde(flatten)]
+    grant: TokenGrant,
+}
+
+/// Tokens listed in a JSON file:
+/// `{"tokens": [{"token": "...", "client_id": "...", "tunnels": ["..."]}]}`.
+/// The file is reloaded when its modification time changes.
+pub struct FileTokenStore {
+    path: PathBuf,
+    tokens: RwLock<HashMap<String, TokenGrant>>,
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl FileTokenStore {
+    pub fn load(path: impl Into<PathBuf>) -> anyhow::Result<Arc<Self>> {
+        let store = Arc::new(Self {
+            path: path.into(),
+            tokens: RwLock::new(HashMap::new()),
+            modified: RwLock::new(None),
+        });
+        store.reload()?;
+        Ok(store)
+    }
+
+    /// Re-reads the file. On error the previous tokens stay in effect.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let modified = std::fs::metadata(&self.path)?.modified().ok();
+        let content = std::fs::read_to_string(&self.path)?;
+        let file: TokenFile = serde_json::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Invalid token file {:?}: {}", self.path, e))?;
+        let tokens: HashMap<String, TokenGrant> = file
+            .tokens
+            .into_iter()
+            .map(|entry| (entry.token, entry.grant))
+            .collect();
+        println!(
+            "[Supernode] Loaded {} tokens from {:?}",
+            tokens.len(),
+            self.path
+        );
+        let removed: Vec<String> = self
+            .tokens
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|token| !tokens.contains_key(*token))
+            .map(|token| token_id(token))
+            .collect();
+        *self.tokens.write().unwrap() = tokens;
+        *self.modified.write().unwrap() = modified;
+        if !removed.is_empty() {
+            close_sessions_of_tokens(&removed, "token revoked");
+        }
+        Ok(())
+    }
+
+    /// Polls the file every `interval` and reloads it when it changed.
+    pub fn watch(self: &Arc<Self>, interval: Duration) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let modified = std::fs::metadata(&store.path)
+                    .ok()
+                    .and_then(|m| m.modified().ok());
+                if modified.is_none() || modified == *store.modified.read().unwrap() {
+                    continue;
+                }
+                if let Err(e) = store.reload() {
+                    eprintln!("[Supernode] Failed to reload tokens: {}", e);
+                }
+            }
+        });
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenStore for FileTokenStore {
+    async fn authorize(&self, token: &str) -> anyhow::Result<Option<TokenGrant>> {
+        Ok(self.tokens.read().unwrap().get(token).cloned())
+    }
+
+    async fn find_token(&self, id: &str) -> Option<String> {
+        let tokens = self.tokens.read().unwrap();
+        tokens.keys().find(|token| token_id(token) == id).cloned()
+    }
+}
+
+/// Claims of a signed token. The client id goes in `sub` like in a JWT.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenClaims {
+    #[serde(rename = "sub")]
+    pub client_id: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tunnels: Vec<String>,
+    /// Issue time, seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat: Option<u64>,
+    /// Expiry, seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+    #[serde(default, skip_serializing_if = "TokenLimits::is_empty")]
+    pub limits: TokenLimits,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub egress: Option<EgressPolicy>,
+}
+
+/// JWT header of every token we issue; tokens are verified as HS256 JWTs.
+const SIGNED_TOKEN_HEADER: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
+
+/// Tokens that carry their own claims, signed with HMAC-SHA256 (JWT HS256), so
+/// they are checked without looking anything up.
+pub struct SignedTokenStore {
+    key: hmac::Key,
+}
+
+impl SignedTokenStore {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+        }
+    }
+
+    pub fn issue(&self, claims: &TokenClaims) -> anyhow::Result<String> {
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(SIGNED_TOKEN_HEADER),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims)?)
+        );
+        Ok(self.sign(&signing_input))
+    }
+
+    fn sign(&self, signing_input: &str) -> String {
+        let signature = hmac::sign(&self.key, signing_input.as_bytes());
+        format!(
+            "{}.{}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signature.as_ref())
+        )
+    }
+
+    pub fn verify(&self, token: &str) -> anyhow::Result<TokenClaims> {
+        let (signing_input, signature) = token
+            .rsplit_once('.')
+            .ok_or(anyhow::anyhow!("malformed token"))?;
+        let (header, claims) = signing_input
+            .split_once('.')
+            .ok_or(anyhow::anyhow!("malformed token"))?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| anyhow::anyhow!("malformed token"))?;
+        hmac::verify(&self.key, signing_input.as_bytes(), &signature)
+            .map_err(|_| anyhow::anyhow!("invalid token signature"))?;
+        let header: serde_json::Value = serde_json::from_slice(
+            &URL_SAFE_NO_PAD
+                .decode(header)
+                .map_err(|_| anyhow::anyhow!("malformed token"))?,
+        )?;
+        if header.get("alg").and_then(|alg| alg.as_str()) != Some("HS256") {
+            return Err(anyhow::anyhow!("unsupported token algorithm"));
+        }
+        let claims: TokenClaims = serde_json::from_slice(
+            &URL_SAFE_NO_PAD
+                .decode(claims)
+                .map_err(|_| anyhow::anyhow!("malformed token"))?,
+        )?;
+        if claims.exp.is_some_and(|exp| exp <= unix_millis() / 1000) {
+            return Err(anyhow::anyhow!("token expired"));
+        }
+        Ok(claims)
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenStore for SignedTokenStore {
+    async fn authorize(&self, token: &str) -> anyhow::Result<Option<TokenGrant>> {
+        if token.split('.').count() != 3 {
+            return Ok(None);
+        }
+        let claims = self.verify(token)?;
+        Ok(Some(TokenGrant {
+            client_id: claims.client_id,
+            tunnels: claims.tunnels,
+            limits: claims.limits,
+            egress: claims.egress,
+        }))
+    }
+
+    /// The token id of a signed token is its signing input, so the token is
+    /// rebuilt by signing it again. Claims are checked later by `authorize`.
+    async fn find_token(&self, token_id: &str) -> Option<String> {
+        (token_id.split('.').count() == 2).then(|| self.sign(token_id))
+    }
+}
+
+/// Asks each store in turn until one of them knows the token.
+pub struct TokenStoreChain(pub Vec<Arc<dyn TokenStore>>);
+
+#[async_trait::async_trait]
+impl TokenStore for TokenStoreChain {
+    async fn authorize(&self, token: &str) -> anyhow::Result<Option<TokenGrant>> {
+        for store in &self.0 {
+            if let Some(grant) = store.authorize(token).await? {
+                return Ok(Some(grant));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn find_token(&self, token_id: &str) -> Option<String> {
+        for store in &self.0 {
+            if let Some(token) = store.find_token(token_id).await {
+                return Some(token);
+            }
+        }
+        None
+    }
+}
+
+static TOKEN_STORE: LazyLock<RwLock<Arc<dyn TokenStore>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(AllowAnyToken)));
+
+/// Sets the registry the supernode checks `Auth` tokens against.
+pub fn set_token_store(store: Arc<dyn TokenStore>) {
+    *TOKEN_STORE.write().unwrap() = store;
+}
+
+pub fn token_store() -> Arc<dyn TokenStore> {
+    TOKEN_STORE.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn file_store_rejects_unknown_and_reloads() {
+        let path = std::env::temp_dir().join(format!("tokens-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"tokens": [{"token": "secret", "client_id": "laptop", "tunnels": ["web"]}]}"#,
+        )
+        .unwrap();
+        let store = FileTokenStore::load(&path).unwrap();
+        let grant = store.authorize("secret").await.unwrap().unwrap();
+        assert_eq!(grant.client_id, "laptop");
+        assert_eq!(grant.tunnel_names(), vec!["web".to_string()]);
+        assert!(store.authorize("other").await.unwrap().is_none());
+
+        std::fs::write(
+            &path,
+            r#"{"tokens": [{"token": "other", "client_id": "phone"}]}"#,
+        )
+        .unwrap();
+        store.reload().unwrap();
+        assert!(store.authorize("secret").await.unwrap().is_none());
+        let grant = store.authorize("other").await.unwrap().unwrap();
+        assert_eq!(grant.tunnel_names(), vec!["phone".to_string()]);
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(store.reload().is_err());
+        assert!(store.authorize("other").await.unwrap().is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn signed_token_round_trip() {
+        let store = SignedTokenStore::new(b"secret");
+        let claims = TokenClaims {
+            client_id: "laptop".to_string(),
+            tunnels: vec!["web".to_string()],
+            exp: Some(unix_millis() / 1000 + 60),
+            limits: TokenLimits {
+                max_streams: Some(4),
+            },
+            ..Default::default()
+        };
+        let token = store.issue(&claims).unwrap();
+        assert_eq!(store.verify(&token).unwrap(), claims);
+
+        assert!(SignedTokenStore::new(b"other").verify(&token).is_err());
+        let (rest, signature) = token.rsplit_once('.').unwrap();
+        let (header, _) = rest.split_once('.').unwrap();
+        let forged_claims = URL_SAFE_NO_PAD.encode(r#"{"sub":"admin"}"#);
+        let forged = format!("{}.{}.{}", header, forged_claims, signature);
+        assert!(store.verify(&forged).is_err());
+    }
+
+    #[tokio::test]
+    async fn stores_find_tokens_by_id() {
+        let signed = SignedTokenStore::new(b"secret");
+        let token = signed
+            .issue(&TokenClaims {
+                client_id: "laptop".to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(token_id(&token).split('.').count(), 2);
+        assert_eq!(signed.find_token(&token_id(&token)).await, Some(token));
+        assert!(signed.find_token(&token_id("plain")).await.is_none());
+
+        let path = std::env::temp_dir().join(format!("token-ids-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"tokens": [{"token": "plain", "client_id": "phone"}]}"#,
+        )
+        .unwrap();
+        let file = FileTokenStore::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!token_id("plain").contains("plain"));
+        assert_eq!(
+            file.find_token(&token_id("plain")).await,
+            Some("plain".to_string())
+        );
+        assert!(file.find_token(&token_id("other")).await.is_none());
+    }
+
+    #[test]
+    fn auth_proof_is_bound_to_token_nonce_and_channel() {
+        let proof = auth_proof("secret", b"nonce", b"exporter");
+        assert!(verify_auth_proof("secret", b"nonce", b"exporter", &proof));
+        assert!(!verify_auth_proof("other", b"nonce", b"exporter", &proof));
+        assert!(!verify_auth_proof("secret", b"other", b"exporter", &proof));
+        assert!(!verify_auth_proof("secret", b"nonce", b"other", &proof));
+    }
+
+    #[test]
+    fn signed_token_expires() {
+        let store = SignedTokenStore::new(b"secret");
+        let token = store
+            .issue(&TokenClaims {
+                client_id: "laptop".to_string(),
+                exp: Some(1),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            store.verify(&token).unwrap_err().to_string(),
+            "token expired"
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use crate::tunnel::common::MAX_DATA_LEN;
+use crate::tunnel::error::ProtocolError;
+use crate::tunnel::packet::{MetaEncoding, TunnelCommandPacket};
+use crate::tunnel::payload::CommandPayload;
+
+/// Highest protocol version spoken by this build.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Lowest protocol version this build still accepts from a peer.
+pub const MIN_PROTOCOL_VERSION: u32 = 0;
+/// Version assumed for peers that predate version negotiation.
+pub const LEGACY_PROTOCOL_VERSION: u32 = 0;
+
+/// Command meta may be sent as CBOR (see `MetaEncoding`).
+pub const CAP_META_CBOR: &str = "meta-cbor";
+
+/// Edges may send `SetSessionMeta` and get a `SetSessionMetaResult` back.
+pub const CAP_SESSION_META: &str = "session-meta";
+
+/// The receiver of a `Forward` reports whether it reached the target with a `ForwardResult`.
+pub const CAP_FORWARD_RESULT: &str = "forward-result";
+
+/// The stream that carried `Auth` stays open as the connection's control stream
+/// (see `ControlChannel`); other streams then only carry `Forward`.
+pub const CAP_CONTROL_STREAM: &str = "control-stream";
+
+/// The supernode may push `Kick`, `Drain`, `Reconfigure` and `Message` on the control stream.
+pub const CAP_SERVER_COMMANDS: &str = "server-commands";
+
+/// Optional protocol features this build can use once both sides agree on them.
+pub const CAPABILITIES: &[&str] = &[
+    CAP_META_CBOR,
+    CAP_SESSION_META,
+    CAP_FORWARD_RESULT,
+    CAP_CONTROL_STREAM,
+    CAP_SERVER_COMMANDS,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolInfo {
+    pub version: u32,
+    pub capabilities: Vec<String>,
+    /// Largest meta the peer accepts from us.
+    pub max_data_len: usize,
+}
+
+impl ProtocolInfo {
+    pub fn legacy() -> Self {
+        Self {
+            version: LEGACY_PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+            max_data_len: MAX_DATA_LEN,
+        }
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    pub fn encoding(&self) -> MetaEncoding {
+        if self.supports(CAP_META_CBOR) {
+            MetaEncoding::Cbor
+        } else {
+            MetaEncoding::Json
+        }
+    }
+
+    /// Builds a packet the peer can read: in the negotiated encoding and within its size limit.
+    pub fn packet<P: CommandPayload>(
+        &self,
+        payload: &P,
+    ) -> Result<TunnelCommandPacket, ProtocolError> {
+        let packet = TunnelCommandPacket::from_payload(payload).with_encoding(self.encoding());
+        if packet.length as usize > self.max_data_len {
+            return Err(ProtocolError::DataTooLarge(packet.length as usize));
+        }
+        Ok(packet)
+    }
+
+    /// Picks the highest version and the capability set common to us and a peer
+    /// advertising `peer_version` (at least `peer_min_version`) and `peer_capabilities`.
+    /// Peers that do not advertise a version are treated as legacy.
+    pub fn negotiate(
+        peer_version: Option<u32>,
+        peer_min_version: Option<u32>,
+        peer_capabilities: &[String],
+        peer_max_data_len: Option<u32>,
+    ) -> anyhow::Result<Self> {
+        let peer_version = peer_version.unwrap_or(LEGACY_PROTOCOL_VERSION);
+        let peer_min_version = peer_min_version.unwrap_or(peer_version);
+        let version = peer_version.min(PROTOCOL_VERSION);
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+            || version < peer_min_version
+        {
+            return Err(anyhow::anyhow!(
+                "No common protocol version: local {}..={}, peer {}..={}",
+                MIN_PROTOCOL_VERSION,
+                PROTOCOL_VERSION,
+                peer_min_version,
+                peer_version
+            ));
+        }
+        let capabilities = peer_capabilities
+            .iter()
+            .filter(|c| CAPABILITIES.contains(&c.as_str()))
+            .cloned()
+            .collect();
+        Ok(Self {
+            version,
+            capabilities,
+            max_data_len: peer_max_data_len.map_or(MAX_DATA_LEN, |len| len as usize),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caps(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn legacy_peer_gets_legacy_protocol() {
+        let info = ProtocolInfo::negotiate(None, None, &[], None).unwrap();
+        assert_eq!(info, ProtocolInfo::legacy());
+        assert_eq!(info.encoding(), MetaEncoding::Json);
+    }
+
+    #[test]
+    fn newer_peer_falls_back_to_our_version() {
+        let info = ProtocolInfo::negotiate(
+            Some(PROTOCOL_VERSION + 3),
+            Some(PROTOCOL_VERSION),
+            &caps(&[CAP_META_CBOR]),
+            Some(1024),
+        )
+        .unwrap();
+        assert_eq!(info.version, PROTOCOL_VERSION);
+        assert_eq!(info.max_data_len, 1024);
+        assert!(info.supports(CAP_META_CBOR));
+
+        let info = ProtocolInfo::negotiate(Some(PROTOCOL_VERSION + 3), Some(0), &[], None).unwrap();
+        assert_eq!(info.version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn peer_requiring_a_newer_version_is_rejected() {
+        let err = ProtocolInfo::negotiate(
+            Some(PROTOCOL_VERSION + 2),
+            Some(PROTOCOL_VERSION + 1),
+            &[],
+            None,
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("No common protocol version"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn unknown_capabilities_are_dropped_and_max_data_len_defaults() {
+        let info = ProtocolInfo::negotiate(
+            Some(PROTOCOL_VERSION),
+            None,
+            &caps(&["teleport", CAP_FORWARD_RESULT, "meta-xml"]),
+            None,
+        )
+        .unwrap();
+        assert_eq!(info.capabilities, caps(&[CAP_FORWARD_RESULT]));
+        assert!(!info.supports("teleport"));
+        assert_eq!(info.max_data_len, MAX_DATA_LEN);
+    }
+}